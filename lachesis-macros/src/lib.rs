@@ -0,0 +1,198 @@
+use syn::visit_mut::VisitMut;
+
+// a loop marked with this is left alone, together with everything inside it
+const OPT_OUT: &str = "no_checkpoint";
+
+#[proc_macro_attribute]
+pub fn preemptible(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    if !attr.is_empty() {
+        return syn::Error::new(
+            proc_macro2::TokenStream::from(attr)
+                .into_iter()
+                .next()
+                .unwrap()
+                .span(),
+            "#[preemptible] takes no arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    if let Ok(mut func) = syn::parse::<syn::ItemFn>(item.clone()) {
+        Checkpoints.visit_block_mut(&mut func.block);
+        func.block
+            .stmts
+            .insert(0, syn::parse_quote!(::lachesis::check_preemption();));
+        return quote::quote!(#func).into();
+    }
+
+    match syn::parse::<syn::ExprClosure>(item) {
+        Ok(closure) => instrument_closure(closure).into(),
+        Err(_) => syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "#[preemptible] can only be used on functions and closures",
+        )
+        .to_compile_error()
+        .into(),
+    }
+}
+
+// closures in expression position cannot carry attributes on stable Rust
+#[proc_macro]
+pub fn preemptible_closure(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let closure = syn::parse_macro_input!(input as syn::ExprClosure);
+    instrument_closure(closure).into()
+}
+
+fn instrument_closure(mut closure: syn::ExprClosure) -> proc_macro2::TokenStream {
+    Checkpoints.visit_expr_mut(&mut closure.body);
+    let body = &closure.body;
+    closure.body = Box::new(syn::parse_quote!({
+        ::lachesis::check_preemption();
+        #body
+    }));
+    quote::quote!(#closure)
+}
+
+// puts a check_preemption() call at the top of every loop body, which is
+// where each iteration jumps back to
+struct Checkpoints;
+
+impl VisitMut for Checkpoints {
+    fn visit_expr_mut(&mut self, expr: &mut syn::Expr) {
+        let attrs = match expr {
+            syn::Expr::ForLoop(l) => &mut l.attrs,
+            syn::Expr::While(l) => &mut l.attrs,
+            syn::Expr::Loop(l) => &mut l.attrs,
+            _ => return syn::visit_mut::visit_expr_mut(self, expr),
+        };
+
+        let before = attrs.len();
+        attrs.retain(|a| !a.path().is_ident(OPT_OUT));
+        if attrs.len() != before {
+            return;
+        }
+
+        syn::visit_mut::visit_expr_mut(self, expr);
+        let body = match expr {
+            syn::Expr::ForLoop(l) => &mut l.body,
+            syn::Expr::While(l) => &mut l.body,
+            syn::Expr::Loop(l) => &mut l.body,
+            _ => unreachable!(),
+        };
+        body.stmts
+            .insert(0, syn::parse_quote!(::lachesis::check_preemption();));
+    }
+
+    // nested fn items are only instrumented if they ask for it themselves
+    fn visit_item_mut(&mut self, _: &mut syn::Item) {}
+}
+
+// `#[lachesis::main(stack_size = 4 * 1024 * 1024, preemption_interval = 10)]`
+// runs the function body as the first green thread of a scheduler built from
+// the arguments; an error exits the process with status 1
+#[proc_macro_attribute]
+pub fn main(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    entry_point(attr, item, Entry::Main)
+}
+
+// like `main`, for `#[test]` functions; errors and panics fail the test
+#[proc_macro_attribute]
+pub fn test(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    entry_point(attr, item, Entry::Test)
+}
+
+enum Entry {
+    Main,
+    Test,
+}
+
+fn entry_point(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+    entry: Entry,
+) -> proc_macro::TokenStream {
+    let args = syn::parse_macro_input!(
+        attr with syn::punctuated::Punctuated::<syn::MetaNameValue, syn::Token![,]>::parse_terminated
+    );
+    let func = syn::parse_macro_input!(item as syn::ItemFn);
+
+    if let Some(arg) = func.sig.inputs.first() {
+        return syn::Error::new_spanned(arg, "entry points cannot take arguments")
+            .to_compile_error()
+            .into();
+    }
+    if let Some(asyncness) = func.sig.asyncness {
+        return syn::Error::new_spanned(asyncness, "entry points cannot be async")
+            .to_compile_error()
+            .into();
+    }
+
+    let mut builder = quote::quote!(::lachesis::Lachesis::builder());
+    for arg in args {
+        let Some(key) = arg.path.get_ident() else {
+            return syn::Error::new_spanned(arg.path, "expected a builder method name")
+                .to_compile_error()
+                .into();
+        };
+        let value = builder_argument(key, arg.value);
+        builder = quote::quote!(#builder.#key(#value));
+    }
+
+    let syn::ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = func;
+    let on_error = match entry {
+        Entry::Main => quote::quote! {{
+            eprintln!("lachesis: {}", error);
+            ::std::process::exit(1)
+        }},
+        Entry::Test => quote::quote!(panic!("lachesis: {}", error)),
+    };
+    let test_attr = match entry {
+        Entry::Main => quote::quote!(),
+        Entry::Test => quote::quote!(#[::core::prelude::v1::test]),
+    };
+
+    quote::quote! {
+        #test_attr
+        #(#attrs)*
+        #vis #sig {
+            match ::lachesis::scheduler::run_entry(#builder.build(), move || #block) {
+                ::std::result::Result::Ok(value) => value,
+                ::std::result::Result::Err(error) => #on_error,
+            }
+        }
+    }
+    .into()
+}
+
+// integer intervals are milliseconds and a bare policy name is a
+// SchedulingPolicy variant; everything else is passed through as written
+fn builder_argument(key: &syn::Ident, value: syn::Expr) -> proc_macro2::TokenStream {
+    match (key.to_string().as_str(), &value) {
+        (
+            "preemption_interval",
+            syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Int(millis),
+                ..
+            }),
+        ) => quote::quote!(::std::time::Duration::from_millis(#millis)),
+        ("policy", syn::Expr::Path(path)) if path.path.get_ident().is_some() => {
+            quote::quote!(::lachesis::SchedulingPolicy::#path)
+        }
+        _ => quote::quote!(#value),
+    }
+}
@@ -0,0 +1,53 @@
+// CPU time accounting for a single green thread
+pub struct Budget {
+    pub limit: crate::types::CpuBudget,
+    pub period_start: std::time::Instant,
+    pub used: std::time::Duration,
+}
+
+pub enum Verdict {
+    Within,
+    Throttle(std::time::Instant),
+    Terminate,
+}
+
+impl Budget {
+    pub fn new(limit: crate::types::CpuBudget) -> Self {
+        Budget {
+            limit,
+            period_start: crate::clock::now(),
+            used: std::time::Duration::ZERO,
+        }
+    }
+
+    pub fn charge(&mut self, elapsed: std::time::Duration, now: std::time::Instant) -> Verdict {
+        if let Some(period) = self.limit.period
+            && now >= self.period_start + period
+        {
+            self.period_start = now;
+            self.used = std::time::Duration::ZERO;
+        }
+
+        self.used += elapsed;
+        if self.used < self.limit.limit {
+            return Verdict::Within;
+        }
+
+        match (self.limit.policy, self.limit.period) {
+            (crate::types::BudgetPolicy::Throttle, Some(period)) => {
+                Verdict::Throttle(self.period_start + period)
+            }
+            (crate::types::BudgetPolicy::Terminate, Some(_) | None) => Verdict::Terminate,
+            // refused by ThreadBuilder, a total budget never refills
+            (crate::types::BudgetPolicy::Throttle, None) => {
+                unreachable!("a total CPU budget with the Throttle policy")
+            }
+        }
+    }
+
+    // called when a throttled thread becomes runnable again
+    pub fn refill(&mut self, now: std::time::Instant) {
+        self.period_start = now;
+        self.used = std::time::Duration::ZERO;
+    }
+}
@@ -0,0 +1,409 @@
+// runs a test many times under different schedules and reports the first
+// one that fails as a trace that replays it
+
+// the choices made at each scheduling point of a run, written as e.g. "0.1.0.2"
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace(Vec<usize>);
+
+impl Trace {
+    fn from_decisions(decisions: &[crate::deterministic::Decision]) -> Self {
+        Trace(decisions.iter().map(|d| d.choice).collect())
+    }
+}
+
+impl std::fmt::Display for Trace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, choice) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{choice}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Trace {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> crate::error::Result<Self> {
+        if s.trim().is_empty() {
+            return Ok(Trace::default());
+        }
+
+        s.trim()
+            .split('.')
+            .map(|choice| choice.parse())
+            .collect::<std::result::Result<Vec<usize>, _>>()
+            .map(Trace)
+            .map_err(|_| crate::error::Error::Configuration(format!("invalid trace: {s:?}")))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Search {
+    // probabilistic concurrency testing: threads get random priorities and
+    // the highest one runs, with depth - 1 points where the running thread
+    // drops to the bottom
+    Pct { iterations: usize, depth: usize },
+    // every schedule that differs from round-robin in at most `delays` decisions
+    Exhaustive { delays: usize },
+}
+
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub trace: Trace,
+    pub message: String,
+    // schedules run up to and including the failing one
+    pub schedules: usize,
+    // the seed of the PCT search that found it, which finds it again
+    pub seed: Option<u64>,
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "schedule {} failed: {}\nreplay it with lachesis::check::replay(\"{}\", ...)",
+            self.schedules, self.message, self.trace
+        )?;
+        if let Some(seed) = self.seed {
+            write!(f, "\nthe PCT search with seed {seed} finds it again")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub schedules: usize,
+    pub failure: Option<Failure>,
+}
+
+pub struct Checker {
+    search: Search,
+    seed: u64,
+    max_schedules: usize,
+    config: crate::types::SchedulerConfig,
+}
+
+impl Checker {
+    pub fn pct(iterations: usize, depth: usize) -> Self {
+        Checker::new(Search::Pct { iterations, depth })
+    }
+
+    pub fn exhaustive(delays: usize) -> Self {
+        Checker::new(Search::Exhaustive { delays })
+    }
+
+    fn new(search: Search) -> Self {
+        Checker {
+            search,
+            seed: 0,
+            max_schedules: usize::MAX,
+            config: crate::types::SchedulerConfig::default(),
+        }
+    }
+
+    // seeds the priorities and change points of PCT
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn max_schedules(mut self, max: usize) -> Self {
+        self.max_schedules = max;
+        self
+    }
+
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.config.default_stack_size = size;
+        self
+    }
+
+    // runs the test once with the schedule of a trace this checker reported,
+    // on the same configuration; its panic is raised again
+    pub fn replay<F>(&self, trace: &str, test: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        replay_with(self.config.clone(), trace, test);
+    }
+
+    pub fn run<F>(&self, test: F) -> crate::error::Result<Report>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let scheduler = crate::scheduler::Lachesis::new(self.config.clone());
+        let test = std::sync::Arc::new(test);
+        let mut schedules = 0;
+
+        match self.search {
+            Search::Pct { iterations, depth } => {
+                let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(self.seed);
+                // checkpoints per run, learned from the runs so far
+                let mut steps = 64;
+
+                while schedules < iterations.min(self.max_schedules) {
+                    let pct = Pct::new(&mut rng, depth, steps);
+                    let (decisions, outcome) = run_once(&scheduler, Box::new(pct), &test)?;
+                    schedules += 1;
+                    if let Err(message) = outcome {
+                        return Ok(failed(&decisions, message, schedules, Some(self.seed)));
+                    }
+                    steps = steps.max(
+                        decisions
+                            .iter()
+                            .filter(|d| d.point == crate::deterministic::Point::Checkpoint)
+                            .count(),
+                    );
+                }
+            }
+            Search::Exhaustive { delays } => {
+                let mut prefix = Vec::new();
+                while schedules < self.max_schedules {
+                    let guided = Guided::new(prefix);
+                    let (decisions, outcome) = run_once(&scheduler, Box::new(guided), &test)?;
+                    schedules += 1;
+                    if let Err(message) = outcome {
+                        return Ok(failed(&decisions, message, schedules, None));
+                    }
+                    match next_prefix(&decisions, delays) {
+                        Some(next) => prefix = next,
+                        None => break,
+                    }
+                }
+            }
+        }
+
+        Ok(Report {
+            schedules,
+            failure: None,
+        })
+    }
+}
+
+fn failed(
+    decisions: &[crate::deterministic::Decision],
+    message: String,
+    schedules: usize,
+    seed: Option<u64>,
+) -> Report {
+    Report {
+        schedules,
+        failure: Some(Failure {
+            trace: Trace::from_decisions(decisions),
+            message,
+            schedules,
+            seed,
+        }),
+    }
+}
+
+// PCT with a thousand schedules of depth 3, panicking on the first failure
+pub fn check<F>(test: F)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let report = Checker::pct(1000, 3)
+        .run(test)
+        .unwrap_or_else(|e| panic!("lachesis::check: {e}"));
+    if let Some(failure) = report.failure {
+        panic!("lachesis::check: {failure}");
+    }
+}
+
+// runs the test once with the schedule of a trace; its panic is raised again.
+// Checker::replay does the same with the checker's configuration
+pub fn replay<F>(trace: &str, test: F)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    replay_with(crate::types::SchedulerConfig::default(), trace, test);
+}
+
+fn replay_with<F>(config: crate::types::SchedulerConfig, trace: &str, test: F)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let trace: Trace = trace
+        .parse()
+        .unwrap_or_else(|e| panic!("lachesis::check: {e}"));
+    let scheduler = crate::scheduler::Lachesis::new(config);
+    let test = std::sync::Arc::new(test);
+    let outcome = run_once(&scheduler, Box::new(Guided::new(trace.0)), &test)
+        .unwrap_or_else(|e| panic!("lachesis::check: {e}"))
+        .1;
+    if let Err(message) = outcome {
+        panic!("{message}");
+    }
+}
+
+type Outcome = std::result::Result<(), String>;
+
+fn run_once<F>(
+    scheduler: &crate::scheduler::Lachesis,
+    strategy: Box<dyn crate::deterministic::Strategy>,
+    test: &std::sync::Arc<F>,
+) -> crate::error::Result<(Vec<crate::deterministic::Decision>, Outcome)>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let test = std::sync::Arc::clone(test);
+    let outcome = std::sync::Arc::new(std::sync::Mutex::new(Ok(())));
+    let slot = std::sync::Arc::clone(&outcome);
+
+    let (decisions, _) = scheduler.run_with(
+        move || {
+            if let Err(payload) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| test()))
+            {
+                *slot.lock().unwrap() = Err(panic_message(payload));
+            }
+        },
+        Some(strategy),
+    )?;
+
+    let mut outcome = outcome.lock().unwrap().clone();
+    if let Some(payload) = crate::runtime::take_thread_panic() {
+        outcome = Err(panic_message(payload));
+    }
+    crate::runtime::take_thread_errors();
+    Ok((decisions, outcome))
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else if let Some(cancelled) = payload.downcast_ref::<crate::types::Cancelled>() {
+        cancelled.0.to_string()
+    } else {
+        "panicked".to_string()
+    }
+}
+
+// the next schedule in depth-first order: bump the last decision that still
+// has an untried choice, as long as the delay bound allows it
+fn next_prefix(decisions: &[crate::deterministic::Decision], delays: usize) -> Option<Vec<usize>> {
+    let mut used: Vec<usize> = Vec::with_capacity(decisions.len());
+    for decision in decisions {
+        let before = used.last().copied().unwrap_or(0);
+        used.push(before + (decision.choice > 0) as usize);
+    }
+
+    (0..decisions.len()).rev().find_map(|i| {
+        let decision = decisions[i];
+        let before = if i == 0 { 0 } else { used[i - 1] };
+        if decision.choice + 1 >= decision.options || before + 1 > delays {
+            return None;
+        }
+
+        let mut prefix: Vec<usize> = decisions[..i].iter().map(|d| d.choice).collect();
+        prefix.push(decision.choice + 1);
+        Some(prefix)
+    })
+}
+
+// follows a list of choices, then makes the round-robin choice
+struct Guided {
+    choices: Vec<usize>,
+    position: usize,
+}
+
+impl Guided {
+    fn new(choices: Vec<usize>) -> Self {
+        Guided {
+            choices,
+            position: 0,
+        }
+    }
+
+    fn next(&mut self) -> usize {
+        let choice = self.choices.get(self.position).copied().unwrap_or(0);
+        self.position += 1;
+        choice
+    }
+}
+
+impl crate::deterministic::Strategy for Guided {
+    fn pick(&mut self, _: &[crate::types::ThreadId]) -> usize {
+        self.next()
+    }
+
+    fn should_yield(&mut self, _: crate::types::ThreadId) -> bool {
+        self.next() == 0
+    }
+}
+
+// a thread that runs this many checkpoints in a row is assumed to be
+// spinning on another one and is moved below every other thread
+const SPIN_LIMIT: usize = 1000;
+
+struct Pct {
+    rng: rand::rngs::StdRng,
+    depth: usize,
+    // threads start above depth, change points put them at 0..depth and a
+    // spinning thread goes below zero
+    priorities: std::collections::HashMap<crate::types::ThreadId, i64>,
+    change_points: Vec<usize>,
+    steps: usize,
+    lowest: i64,
+    running: Option<(crate::types::ThreadId, usize)>,
+}
+
+impl Pct {
+    fn new(rng: &mut rand::rngs::StdRng, depth: usize, steps: usize) -> Self {
+        let change_points = (1..depth)
+            .map(|_| rand::Rng::random_range(&mut *rng, 1..=steps.max(1)))
+            .collect();
+        Pct {
+            rng: <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(rand::Rng::random(rng)),
+            depth,
+            priorities: std::collections::HashMap::new(),
+            change_points,
+            steps: 0,
+            lowest: 0,
+            running: None,
+        }
+    }
+}
+
+impl crate::deterministic::Strategy for Pct {
+    fn pick(&mut self, ready: &[crate::types::ThreadId]) -> usize {
+        for &id in ready {
+            if !self.priorities.contains_key(&id) {
+                let priority =
+                    self.depth as i64 + rand::Rng::random_range(&mut self.rng, 0..i64::MAX / 2);
+                self.priorities.insert(id, priority);
+            }
+        }
+
+        (0..ready.len())
+            .max_by_key(|&i| self.priorities[&ready[i]])
+            .unwrap_or(0)
+    }
+
+    fn should_yield(&mut self, running: crate::types::ThreadId) -> bool {
+        self.steps += 1;
+        let streak = match self.running {
+            Some((id, streak)) if id == running => streak + 1,
+            _ => 1,
+        };
+        self.running = Some((running, streak));
+
+        if let Some(point) = self.change_points.iter().position(|&p| p == self.steps) {
+            self.priorities.insert(running, point as i64);
+            return true;
+        }
+
+        if streak >= SPIN_LIMIT {
+            self.lowest -= 1;
+            self.priorities.insert(running, self.lowest);
+            self.running = None;
+            return true;
+        }
+
+        false
+    }
+}
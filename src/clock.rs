@@ -0,0 +1,95 @@
+// the time source of sleeps, budgets and time slices; a virtual clock only
+// moves when every thread is asleep, jumping to the first wake-up, or when it
+// is advanced by hand
+#[derive(Default)]
+pub struct State {
+    simulated: bool,
+    // virtual time since base()
+    elapsed_ns: u64,
+}
+
+fn base() -> std::time::Instant {
+    static BASE: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    *BASE.get_or_init(std::time::Instant::now)
+}
+
+// the clock of the runtime on this OS thread
+fn state() -> *mut State {
+    let runtime = crate::runtime::current();
+    if runtime.is_null() {
+        return std::ptr::null_mut();
+    }
+    unsafe { &raw mut (*runtime).clock }
+}
+
+// every run with a virtual clock starts at the same instant
+pub fn set_virtual(enabled: bool) {
+    let state = state();
+    if !state.is_null() {
+        unsafe {
+            (*state).simulated = enabled;
+            (*state).elapsed_ns = 0;
+        }
+    }
+}
+
+pub fn is_virtual() -> bool {
+    let state = state();
+    !state.is_null() && unsafe { (*state).simulated }
+}
+
+pub fn now() -> std::time::Instant {
+    base() + std::time::Duration::from_nanos(now_ns())
+}
+
+// time since an arbitrary fixed point; the timer thread has no runtime and
+// reads the real clock
+pub fn now_ns() -> u64 {
+    if is_virtual() {
+        unsafe { (*state()).elapsed_ns }
+    } else {
+        base().elapsed().as_nanos() as u64
+    }
+}
+
+// move virtual time forward; threads whose sleep has run out wake at their
+// next scheduling point
+pub fn advance(by: std::time::Duration) -> crate::error::Result<()> {
+    if !is_virtual() {
+        return Err(crate::error::Error::Configuration(
+            "only a virtual clock can be advanced".to_string(),
+        ));
+    }
+    unsafe { (*state()).elapsed_ns += by.as_nanos() as u64 };
+    Ok(())
+}
+
+// called when no thread can run before deadline; a Handle spawning work
+// may end the wait early
+pub fn wait_until(deadline: std::time::Instant) {
+    if is_virtual() {
+        let ns = deadline.saturating_duration_since(base()).as_nanos() as u64;
+        unsafe { (*state()).elapsed_ns = (*state()).elapsed_ns.max(ns) };
+    } else {
+        std::thread::park_timeout(deadline.saturating_duration_since(now()));
+    }
+}
+
+// suspend the running green thread until deadline; outside the scheduler
+// this blocks the OS thread
+pub fn sleep_until(deadline: std::time::Instant) {
+    if crate::runtime::current_thread_id() == 0 {
+        while now() < deadline {
+            wait_until(deadline);
+        }
+        return;
+    }
+
+    while now() < deadline {
+        crate::runtime::sleep_current(deadline);
+    }
+}
+
+pub fn sleep(duration: std::time::Duration) {
+    sleep_until(now() + duration);
+}
@@ -1,4 +1,6 @@
 pub const PAGE_SIZE: usize = 4 * 1024; // 4KiB
+// room for the frames of entry_point
+pub const MIN_STACK_SIZE: usize = 4 * PAGE_SIZE;
 
 #[cfg(target_arch = "x86_64")]
 #[repr(C)]
@@ -80,12 +82,28 @@ unsafe extern "C" {
 
 pub struct Context {
     pub regs: Registers,
-    pub stack: *mut u8,
-    pub stack_layout: std::alloc::Layout,
+    pub stack: crate::stack::Stack,
+    // as asked for; the stack itself is rounded up to its size class
+    pub stack_size: usize,
+    pub name: Option<String>,
     pub entry: Option<crate::types::Entry>,
     pub id: u64,
     pub state: crate::types::ThreadState,
     pub executable: Option<Box<dyn crate::types::Executable>>,
+    pub quantum: Option<std::time::Duration>,
+    pub cpu_time: std::time::Duration,
+    pub budget: Option<crate::budget::Budget>,
+    pub wake_at: Option<std::time::Instant>,
+    pub group: crate::group::GroupId,
+    pub cancelled: Option<crate::error::Error>,
+    // no-preempt depth while switched out; a new thread holds the one
+    // taken by the switch that starts it
+    pub preempt_depth: usize,
+    // async_preemptible sections the thread is in; only inside one may the
+    // timer signal switch it out
+    pub async_sections: usize,
+    // does not keep the scheduler running
+    pub daemon: bool,
 }
 
 impl Context {
@@ -97,31 +115,44 @@ impl Context {
         &self.regs as *const Registers
     }
 
-    pub fn new(func: Option<crate::types::Entry>, stack_size: usize, id: u64) -> Self {
-        let layout = std::alloc::Layout::from_size_align(stack_size, PAGE_SIZE).unwrap();
-        let stack = unsafe { std::alloc::alloc(layout) };
+    pub fn new(
+        func: Option<crate::types::Entry>,
+        stack_size: usize,
+        id: u64,
+    ) -> crate::error::Result<Self> {
+        let stack = crate::stack::take(stack_size)?;
 
-        // set up guard page for stack overflow protection
-        unsafe {
-            let non_null_ptr = std::ptr::NonNull::new(stack as *mut std::ffi::c_void).unwrap();
-            nix::sys::mman::mprotect(
-                non_null_ptr,
-                PAGE_SIZE,
-                nix::sys::mman::ProtFlags::PROT_NONE,
-            )
-            .unwrap();
+        // entry_point is jumped to as if it had been called, so leave room for
+        // a null return address at the top of the stack
+        #[cfg(target_arch = "x86_64")]
+        let stack_top = unsafe {
+            let top = stack.top().sub(8) as *mut u64;
+            top.write(0);
+            top as u64
         };
+        #[cfg(target_arch = "aarch64")]
+        let stack_top = stack.top() as u64;
 
-        let regs = Registers::new(stack as u64 + stack_size as u64);
+        let regs = Registers::new(stack_top);
 
-        Context {
+        Ok(Context {
             regs,
             stack,
-            stack_layout: layout,
+            stack_size,
+            name: None,
             entry: func,
             id,
             state: crate::ThreadState::Ready,
             executable: None,
-        }
+            quantum: None,
+            cpu_time: std::time::Duration::ZERO,
+            budget: None,
+            wake_at: None,
+            group: crate::group::ROOT_GROUP,
+            cancelled: None,
+            preempt_depth: 1,
+            async_sections: 0,
+            daemon: false,
+        })
     }
 }
@@ -0,0 +1,149 @@
+// deterministic scheduling: which ready thread runs next and whether a
+// checkpoint yields are decided by a strategy, and thread ids come from a
+// seeded PRNG, so the same decisions replay the same run
+pub trait Strategy {
+    // index into ready of the thread to run next
+    fn pick(&mut self, ready: &[crate::types::ThreadId]) -> usize;
+
+    // whether the running thread gives up the CPU at this checkpoint
+    fn should_yield(&mut self, running: crate::types::ThreadId) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Point {
+    // choosing among the ready threads, by index
+    Pick,
+    // a check_preemption call: 0 yields, 1 runs on
+    Checkpoint,
+}
+
+// one decision of a run; choice 0 is what a plain round-robin run would do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub point: Point,
+    pub choice: usize,
+    pub options: usize,
+}
+
+// the deterministic mode of one runtime, off without a strategy
+#[derive(Default)]
+pub struct State {
+    strategy: Option<Box<dyn Strategy>>,
+    ids: Option<rand::rngs::StdRng>,
+    seed: Option<u64>,
+    decisions: Vec<Decision>,
+}
+
+fn state() -> *mut State {
+    let runtime = crate::runtime::current();
+    if runtime.is_null() {
+        return std::ptr::null_mut();
+    }
+    unsafe { &raw mut (*runtime).deterministic }
+}
+
+pub const SEED_VAR: &str = "LACHESIS_SEED";
+
+// the seed of a run: LACHESIS_SEED wins over the configured one, and without
+// either a fresh one is drawn
+pub fn resolve_seed(configured: Option<u64>) -> crate::error::Result<u64> {
+    match std::env::var(SEED_VAR) {
+        Ok(value) => value.trim().parse().map_err(|_| {
+            crate::error::Error::Configuration(format!("{SEED_VAR} is not a u64: {value:?}"))
+        }),
+        Err(_) => Ok(configured.unwrap_or_else(rand::random)),
+    }
+}
+
+// every decision is a draw from a PRNG seeded with seed
+pub struct Random(rand::rngs::StdRng);
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Random(<rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(
+            seed,
+        ))
+    }
+}
+
+impl Strategy for Random {
+    fn pick(&mut self, ready: &[crate::types::ThreadId]) -> usize {
+        rand::Rng::random_range(&mut self.0, 0..ready.len())
+    }
+
+    // a coin flip per checkpoint
+    fn should_yield(&mut self, _: crate::types::ThreadId) -> bool {
+        rand::Rng::random_bool(&mut self.0, 0.5)
+    }
+}
+
+pub fn install(strategy: Box<dyn Strategy>, seed: Option<u64>) {
+    unsafe {
+        (*crate::runtime::runtime()).deterministic = State {
+            strategy: Some(strategy),
+            ids: Some(<rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(
+                seed.unwrap_or(0),
+            )),
+            seed,
+            decisions: Vec::new(),
+        };
+    }
+}
+
+// back to the policy and the timer; returns the decisions that were made
+pub fn uninstall() -> Vec<Decision> {
+    let state = state();
+    if state.is_null() {
+        return Vec::new();
+    }
+    unsafe { std::mem::take(&mut *state).decisions }
+}
+
+pub fn seed() -> Option<u64> {
+    let state = state();
+    if state.is_null() {
+        return None;
+    }
+    unsafe { (*state).seed }
+}
+
+pub fn is_enabled() -> bool {
+    let state = state();
+    !state.is_null() && unsafe { (*state).strategy.is_some() }
+}
+
+fn record(state: &mut State, point: Point, choice: usize, options: usize) {
+    state.decisions.push(Decision {
+        point,
+        choice,
+        options,
+    });
+}
+
+pub fn pick(ready: &[crate::types::ThreadId]) -> Option<usize> {
+    if ready.is_empty() {
+        return None;
+    }
+
+    let state = unsafe { state().as_mut()? };
+    let choice = state.strategy.as_mut()?.pick(ready).min(ready.len() - 1);
+    record(state, Point::Pick, choice, ready.len());
+    Some(choice)
+}
+
+pub fn should_yield(running: crate::types::ThreadId) -> bool {
+    let Some(state) = (unsafe { state().as_mut() }) else {
+        return false;
+    };
+    let Some(strategy) = state.strategy.as_mut() else {
+        return false;
+    };
+    let yields = strategy.should_yield(running);
+    record(state, Point::Checkpoint, !yields as usize, 2);
+    yields
+}
+
+pub fn next_id() -> Option<u64> {
+    let state = unsafe { state().as_mut()? };
+    state.ids.as_mut().map(rand::Rng::random)
+}
@@ -21,11 +21,30 @@ pub enum Error {
     #[error("Thread spawn failed")]
     SpawnFailed,
 
-    #[error("System resource error: {0}")]
-    SystemResource(String),
+    #[error("System resource error: {what}: {source}")]
+    SystemResource {
+        what: String,
+        #[source]
+        source: std::io::Error,
+    },
 
     #[error("Configuration error: {0}")]
     Configuration(String),
+
+    #[error("Thread {id} exceeded its CPU budget after {used:?}")]
+    BudgetExceeded { id: u64, used: std::time::Duration },
+
+    #[error("Thread {0} was cancelled")]
+    Cancelled(u64),
+
+    #[error("Spawn refused at {threads} live threads using {stack_memory} bytes of stack")]
+    CapacityExceeded { threads: usize, stack_memory: usize },
+
+    #[error("Replay diverged from the recording: {0}")]
+    ReplayDiverged(String),
+
+    #[error("Scheduler shut down before the main thread finished")]
+    Shutdown,
 }
 
 impl Error {
@@ -39,7 +58,12 @@ impl Error {
             | Error::Deadlock
             | Error::LockFailed
             | Error::SpawnFailed
-            | Error::SystemResource(_) => true,
+            | Error::SystemResource { .. }
+            | Error::BudgetExceeded { .. }
+            | Error::Cancelled(_)
+            | Error::CapacityExceeded { .. }
+            | Error::ReplayDiverged(_)
+            | Error::Shutdown => true,
         }
     }
 }
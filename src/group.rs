@@ -0,0 +1,292 @@
+pub type GroupId = u64;
+
+pub const ROOT_GROUP: GroupId = 0;
+pub const DEFAULT_SHARES: u64 = 1024;
+
+pub struct GroupInfo {
+    pub parent: Option<GroupId>,
+    pub shares: u64,
+    // weighted CPU time of the group as seen by its siblings
+    pub vruntime: u128,
+    // weighted CPU time of the threads placed directly in this group, which
+    // compete with its child groups as one more child with the default shares
+    pub self_vruntime: u128,
+    pub cpu_time: std::time::Duration,
+    pub spawned: usize,
+    // whether the group, and the threads placed directly in it, had runnable
+    // threads at the last pick
+    pub runnable: bool,
+    pub self_runnable: bool,
+}
+
+// the groups of one runtime
+pub struct Groups {
+    groups: std::collections::BTreeMap<GroupId, GroupInfo>,
+    next: GroupId,
+}
+
+impl Groups {
+    pub fn new() -> Self {
+        let mut groups = std::collections::BTreeMap::new();
+        groups.insert(
+            ROOT_GROUP,
+            GroupInfo {
+                parent: None,
+                shares: DEFAULT_SHARES,
+                vruntime: 0,
+                self_vruntime: 0,
+                cpu_time: std::time::Duration::ZERO,
+                spawned: 0,
+                runnable: false,
+                self_runnable: false,
+            },
+        );
+        Groups { groups, next: 1 }
+    }
+}
+
+fn groups() -> *mut std::collections::BTreeMap<GroupId, GroupInfo> {
+    unsafe { &raw mut (*crate::runtime::runtime()).groups.groups }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ThreadGroup {
+    id: GroupId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupStats {
+    pub cpu_time: std::time::Duration,
+    pub live_threads: usize,
+    pub spawned_threads: usize,
+}
+
+impl ThreadGroup {
+    pub fn root() -> Self {
+        ThreadGroup { id: ROOT_GROUP }
+    }
+
+    // the group of the calling green thread
+    pub fn current() -> Self {
+        ThreadGroup {
+            id: crate::runtime::current_group(),
+        }
+    }
+
+    // create a group nested in the group of the calling green thread
+    pub fn new(shares: u64) -> Self {
+        Self::current().child(shares)
+    }
+
+    pub fn child(&self, shares: u64) -> Self {
+        crate::preempt::internal(|| unsafe {
+            let groups_ptr = groups();
+            let next_ptr = &raw mut (*crate::runtime::runtime()).groups.next;
+            let id = *next_ptr;
+            *next_ptr += 1;
+
+            // start level with the siblings so the new group cannot monopolize them
+            let vruntime = (*groups_ptr)
+                .values()
+                .filter(|g| g.parent == Some(self.id))
+                .map(|g| g.vruntime)
+                .min()
+                .unwrap_or(0);
+
+            (*groups_ptr).insert(
+                id,
+                GroupInfo {
+                    parent: Some(self.id),
+                    shares: shares.max(1),
+                    vruntime,
+                    self_vruntime: 0,
+                    cpu_time: std::time::Duration::ZERO,
+                    spawned: 0,
+                    runnable: false,
+                    self_runnable: false,
+                },
+            );
+
+            ThreadGroup { id }
+        })
+    }
+
+    pub fn id(&self) -> GroupId {
+        self.id
+    }
+
+    pub fn set_shares(&self, shares: u64) {
+        crate::preempt::internal(|| unsafe {
+            let groups_ptr = groups();
+            if let Some(group) = (*groups_ptr).get_mut(&self.id) {
+                group.shares = shares.max(1);
+            }
+        })
+    }
+
+    pub fn spawn<F>(&self, func: F) -> crate::types::ThreadId
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        crate::thread::ThreadBuilder::new().group(self).spawn(func)
+    }
+
+    // aggregate over this group and all of its descendants
+    pub fn stats(&self) -> GroupStats {
+        crate::preempt::internal(|| unsafe {
+            let groups_ptr = groups();
+            let mut stats = GroupStats {
+                cpu_time: std::time::Duration::ZERO,
+                live_threads: crate::runtime::count_threads(|g| is_descendant(g, self.id)),
+                spawned_threads: 0,
+            };
+
+            for (&id, group) in (*groups_ptr).iter() {
+                if is_descendant(id, self.id) {
+                    stats.cpu_time += group.cpu_time;
+                    stats.spawned_threads += group.spawned;
+                }
+            }
+
+            stats
+        })
+    }
+
+    // cancel every thread of this group and its descendants;
+    // each one unwinds the next time it would run
+    pub fn cancel(&self) {
+        crate::runtime::cancel_threads(|g| is_descendant(g, self.id));
+    }
+}
+
+pub fn is_descendant(group: GroupId, ancestor: GroupId) -> bool {
+    unsafe {
+        let groups_ptr = groups();
+        let mut current = Some(group);
+        while let Some(id) = current {
+            if id == ancestor {
+                return true;
+            }
+            current = (*groups_ptr).get(&id).and_then(|g| g.parent);
+        }
+        false
+    }
+}
+
+pub fn record_spawn(group: GroupId) {
+    unsafe {
+        let groups_ptr = groups();
+        if let Some(info) = (*groups_ptr).get_mut(&group) {
+            info.spawned += 1;
+        }
+    }
+}
+
+pub fn charge(group: GroupId, elapsed: std::time::Duration) {
+    unsafe {
+        let groups_ptr = groups();
+        if let Some(info) = (*groups_ptr).get_mut(&group) {
+            info.self_vruntime += weighted(elapsed, DEFAULT_SHARES);
+        }
+
+        let mut current = Some(group);
+        while let Some(id) = current {
+            let Some(info) = (*groups_ptr).get_mut(&id) else {
+                break;
+            };
+            info.cpu_time += elapsed;
+            info.vruntime += weighted(elapsed, info.shares);
+            current = info.parent;
+        }
+    }
+}
+
+// CPU time scaled by shares, the same way on every level
+fn weighted(elapsed: std::time::Duration, shares: u64) -> u128 {
+    elapsed.as_nanos() * DEFAULT_SHARES as u128 / shares as u128
+}
+
+// descend from the root, at each level taking the child group (or the level's own
+// threads) that has received the least weighted CPU time, then return the index of
+// the first runnable thread of the chosen group
+pub fn pick(runnable: &[(usize, GroupId)]) -> Option<usize> {
+    unsafe {
+        let groups_ptr = groups();
+
+        let mut active = std::collections::HashSet::new();
+        for &(_, group) in runnable {
+            let mut current = Some(group);
+            while let Some(id) = current {
+                if !active.insert(id) {
+                    break;
+                }
+                current = (*groups_ptr).get(&id).and_then(|g| g.parent);
+            }
+        }
+        let own: std::collections::HashSet<GroupId> = runnable.iter().map(|&(_, g)| g).collect();
+        wake(&active, &own);
+
+        let mut level = ROOT_GROUP;
+        loop {
+            let own = runnable.iter().find(|&&(_, g)| g == level).map(|&(i, _)| i);
+            let own_vruntime = (*groups_ptr).get(&level).map_or(0, |g| g.self_vruntime);
+
+            let child = (*groups_ptr)
+                .iter()
+                .filter(|(id, g)| g.parent == Some(level) && active.contains(*id))
+                .min_by_key(|(_, g)| g.vruntime);
+
+            match (own, child) {
+                (Some(index), Some((_, group))) if own_vruntime <= group.vruntime => {
+                    return Some(index);
+                }
+                (_, Some((&id, _))) => level = id,
+                (Some(index), None) => return Some(index),
+                (None, None) => return runnable.first().map(|&(i, _)| i),
+            }
+        }
+    }
+}
+
+// a group, or a level's own threads, runnable again after being idle starts
+// level with what stayed runnable beside it, like the min_vruntime of CFS, so
+// it cannot make up for the time it was idle by monopolizing its siblings
+unsafe fn wake(
+    active: &std::collections::HashSet<GroupId>,
+    own: &std::collections::HashSet<GroupId>,
+) {
+    unsafe {
+        let groups_ptr = groups();
+        for &level in active {
+            let mut floor = (*groups_ptr)
+                .get(&level)
+                .filter(|g| g.self_runnable && own.contains(&level))
+                .map(|g| g.self_vruntime);
+            for (id, group) in (*groups_ptr).iter() {
+                if group.parent == Some(level) && group.runnable && active.contains(id) {
+                    floor = Some(floor.map_or(group.vruntime, |f| f.min(group.vruntime)));
+                }
+            }
+            let Some(floor) = floor else {
+                continue;
+            };
+
+            if let Some(group) = (*groups_ptr).get_mut(&level)
+                && !group.self_runnable
+                && own.contains(&level)
+            {
+                group.self_vruntime = group.self_vruntime.max(floor);
+            }
+            for (id, group) in (*groups_ptr).iter_mut() {
+                if group.parent == Some(level) && !group.runnable && active.contains(id) {
+                    group.vruntime = group.vruntime.max(floor);
+                }
+            }
+        }
+
+        for (id, group) in (*groups_ptr).iter_mut() {
+            group.runnable = active.contains(id);
+            group.self_runnable = own.contains(id);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+// a way into a scheduler from other OS threads; spawned work is pushed on a
+// lock-free stack and becomes green threads at the scheduler's next switch
+#[derive(Clone)]
+pub struct Handle {
+    injector: std::sync::Arc<Injector>,
+}
+
+struct Injected {
+    task: crate::types::Task,
+    stack_size: Option<usize>,
+    next: *mut Injected,
+}
+
+pub struct Injector {
+    // newest first
+    head: std::sync::atomic::AtomicPtr<Injected>,
+    // the OS thread of the running scheduler, unparked when work arrives;
+    // published without a lock so that a push never blocks, e.g. from a
+    // signal thread while the scheduler holds the allocator
+    scheduler: std::sync::atomic::AtomicPtr<std::thread::Thread>,
+    // pushes that may still be reading scheduler
+    wakers: std::sync::atomic::AtomicUsize,
+}
+
+impl Default for Injector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Injector {
+    pub fn new() -> Self {
+        Injector {
+            head: std::sync::atomic::AtomicPtr::new(std::ptr::null_mut()),
+            scheduler: std::sync::atomic::AtomicPtr::new(std::ptr::null_mut()),
+            wakers: std::sync::atomic::AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, task: crate::types::Task, stack_size: Option<usize>) {
+        let node = Box::into_raw(Box::new(Injected {
+            task,
+            stack_size,
+            next: std::ptr::null_mut(),
+        }));
+
+        let mut head = self.head.load(std::sync::atomic::Ordering::Relaxed);
+        loop {
+            unsafe { (*node).next = head };
+            match self.head.compare_exchange_weak(
+                head,
+                node,
+                std::sync::atomic::Ordering::Release,
+                std::sync::atomic::Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+
+        self.wakers
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let thread = self.scheduler.load(std::sync::atomic::Ordering::SeqCst);
+        if !thread.is_null() {
+            unsafe { (*thread).unpark() };
+        }
+        self.wakers
+            .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    // everything pushed so far, oldest first
+    fn take(&self) -> Vec<(crate::types::Task, Option<usize>)> {
+        let mut node = self
+            .head
+            .swap(std::ptr::null_mut(), std::sync::atomic::Ordering::Acquire);
+        let mut tasks = Vec::new();
+        while !node.is_null() {
+            let injected = unsafe { Box::from_raw(node) };
+            node = injected.next;
+            tasks.push((injected.task, injected.stack_size));
+        }
+        tasks.reverse();
+        tasks
+    }
+
+    pub fn attach(&self) {
+        self.publish(Box::into_raw(Box::new(std::thread::current())));
+    }
+
+    pub fn detach(&self) {
+        self.publish(std::ptr::null_mut());
+    }
+
+    // swap the scheduler thread and free the old one once no push can be
+    // reading it; a push counts itself before it loads the pointer, so one
+    // that still got the old thread is seen here and waited out
+    fn publish(&self, thread: *mut std::thread::Thread) {
+        let old = self
+            .scheduler
+            .swap(thread, std::sync::atomic::Ordering::SeqCst);
+        if old.is_null() {
+            return;
+        }
+        while self.wakers.load(std::sync::atomic::Ordering::SeqCst) != 0 {
+            std::hint::spin_loop();
+        }
+        drop(unsafe { Box::from_raw(old) });
+    }
+}
+
+impl Drop for Injector {
+    fn drop(&mut self) {
+        self.take();
+        self.detach();
+    }
+}
+
+// the node pointers are only followed by whoever swapped them out of head
+unsafe impl Send for Injector {}
+unsafe impl Sync for Injector {}
+
+impl Handle {
+    pub fn new(injector: std::sync::Arc<Injector>) -> Self {
+        Handle { injector }
+    }
+
+    // callable from any OS thread; the thread starts in the root group once
+    // the scheduler drains the queue, and work spawned while the scheduler is
+    // not running waits for its next run
+    pub fn spawn<F>(&self, func: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.injector.push(Box::new(func), None);
+    }
+
+    pub fn spawn_with_stack_size<F>(&self, func: F, stack_size: usize)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.injector.push(Box::new(func), Some(stack_size));
+    }
+}
+
+// turn injected work into ready threads of the current runtime; called with
+// preemption disabled from the scheduler
+pub fn drain() {
+    unsafe {
+        let runtime = crate::runtime::runtime();
+        let Some(injector) = (*runtime).injector.clone() else {
+            return;
+        };
+        if injector
+            .head
+            .load(std::sync::atomic::Ordering::Relaxed)
+            .is_null()
+        {
+            return;
+        }
+
+        for (task, stack_size) in injector.take() {
+            let stack_size = stack_size.unwrap_or((*runtime).default_stack_size);
+            let mut ctx = match crate::runtime::new_context(stack_size) {
+                Ok(ctx) => ctx,
+                Err(e) => {
+                    (*runtime).thread_errors.push(e);
+                    continue;
+                }
+            };
+            ctx.executable = Some(Box::new(task));
+            ctx.group = crate::group::ROOT_GROUP;
+            ctx.state = crate::ThreadState::Ready;
+            crate::group::record_spawn(ctx.group);
+            crate::record::on_spawn(ctx.id);
+            crate::runtime::on_spawn(&mut ctx);
+            (*runtime).contexts.push_back(ctx);
+        }
+    }
+}
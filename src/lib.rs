@@ -1,17 +1,52 @@
+extern crate self as lachesis;
+
+pub mod check;
 pub mod scheduler;
 
+mod budget;
+mod clock;
 mod context;
 mod cooperative;
+mod deterministic;
 mod error;
+mod group;
+mod handle;
+mod manual;
+mod overflow;
+mod preempt;
+mod record;
 mod runtime;
+mod stack;
+mod thread;
 mod timer;
 mod types;
 
+pub use clock::{advance, now, sleep, sleep_until};
 pub use cooperative::CooperativeScheduler;
-pub use runtime::{spawn, spawn_from_main};
+pub use error::{Error, Result};
+pub use group::{GroupId, GroupStats, ThreadGroup};
+pub use handle::Handle;
+pub use lachesis_macros::{main, preemptible, preemptible_closure, test};
+pub use manual::{ManualScheduler, Step};
+pub use nix::sys::signal::Signal;
+pub use preempt::{PreemptGuard, PreemptSafeAlloc, async_preemptible, no_preempt};
+pub use runtime::{spawn, spawn_from_main, spawn_wait, take_thread_errors, try_spawn, yield_to};
 pub use scheduler::Lachesis;
-pub use timer::{check_preemption, disable_preemption, enable_preemption_with_interval};
-pub use types::{SchedulerConfig, Task, ThreadId, ThreadInfo, ThreadState};
+pub use stack::{cached_stack_bytes, trim_stack_pool};
+pub use thread::ThreadBuilder;
+pub use timer::{
+    MIN_QUANTUM, check_preemption, disable_preemption, enable_preemption_with_interval,
+    preemption_interval, set_preemption_interval,
+};
+pub use types::{
+    AdaptiveQuantum, BudgetPolicy, CpuBudget, RunReport, SchedulerConfig, SchedulingPolicy,
+    ShutdownPolicy, Task, ThreadId, ThreadInfo, ThreadState, TimerBackend, TimerClock,
+};
+
+#[cfg(test)]
+#[global_allocator]
+static ALLOC: crate::PreemptSafeAlloc<std::alloc::System> =
+    crate::PreemptSafeAlloc(std::alloc::System);
 
 #[cfg(test)]
 mod tests {
@@ -44,6 +79,1598 @@ mod tests {
             }
         }
 
-        crate::runtime::spawn_from_main(test_thread, 2 * 1024 * 1024, 10);
+        crate::runtime::spawn_from_main(
+            test_thread,
+            2 * 1024 * 1024,
+            std::time::Duration::from_millis(10),
+        );
+    }
+
+    #[test]
+    fn test_thread_quantum() {
+        let adaptive = crate::AdaptiveQuantum {
+            min: std::time::Duration::from_millis(2),
+            max: std::time::Duration::from_millis(20),
+        };
+        assert_eq!(
+            adaptive.quantum_for(1),
+            std::time::Duration::from_millis(20)
+        );
+        assert_eq!(adaptive.quantum_for(4), std::time::Duration::from_millis(5));
+        assert_eq!(
+            adaptive.quantum_for(100),
+            std::time::Duration::from_millis(2)
+        );
+
+        let counter = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter_clone = std::sync::Arc::clone(&counter);
+
+        let scheduler = crate::Lachesis::builder()
+            .preemption_interval(std::time::Duration::from_millis(10))
+            .adaptive_quantum(
+                std::time::Duration::from_millis(1),
+                std::time::Duration::from_millis(8),
+            )
+            .build();
+        scheduler
+            .run(move || {
+                for _ in 0..3 {
+                    let counter = std::sync::Arc::clone(&counter_clone);
+                    crate::ThreadBuilder::new()
+                        .quantum(std::time::Duration::from_millis(1))
+                        .spawn(move || {
+                            for j in 0..100000 {
+                                std::hint::black_box(j);
+                                crate::check_preemption();
+                            }
+                            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        });
+                }
+
+                crate::set_preemption_interval(std::time::Duration::from_millis(5)).unwrap();
+                assert_eq!(
+                    crate::preemption_interval(),
+                    std::time::Duration::from_millis(5)
+                );
+
+                // a slice shorter than the timer can sensibly tick is refused
+                for quantum in [std::time::Duration::ZERO, crate::MIN_QUANTUM / 2] {
+                    assert!(matches!(
+                        crate::set_preemption_interval(quantum),
+                        Err(crate::Error::Configuration(_))
+                    ));
+                    assert!(matches!(
+                        crate::ThreadBuilder::new()
+                            .quantum(quantum)
+                            .try_spawn(|| {}),
+                        Err(crate::Error::Configuration(_))
+                    ));
+                }
+                assert_eq!(
+                    crate::preemption_interval(),
+                    std::time::Duration::from_millis(5)
+                );
+            })
+            .unwrap();
+
+        assert_eq!(counter.load(std::sync::atomic::Ordering::SeqCst), 3);
+        assert!(matches!(
+            crate::set_preemption_interval(std::time::Duration::from_millis(5)),
+            Err(crate::Error::NotInitialized)
+        ));
+        assert!(matches!(
+            crate::Lachesis::builder()
+                .preemption_interval(std::time::Duration::ZERO)
+                .build()
+                .run(|| {}),
+            Err(crate::Error::Configuration(_))
+        ));
+
+        // a short quantum ends the slice on time, even though the timer was
+        // waiting for the long slice before it
+        let slice = std::sync::Arc::new(std::sync::Mutex::new(std::time::Duration::ZERO));
+        let slice_clone = std::sync::Arc::clone(&slice);
+        crate::Lachesis::builder()
+            .preemption_interval(std::time::Duration::from_millis(200))
+            .build()
+            .run(move || {
+                // let the timer settle on main's slice
+                let start = std::time::Instant::now();
+                while start.elapsed() < std::time::Duration::from_millis(30) {
+                    std::hint::spin_loop();
+                }
+
+                let switched = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+                let switched_clone = std::sync::Arc::clone(&switched);
+                crate::ThreadBuilder::new()
+                    .quantum(std::time::Duration::from_millis(2))
+                    .spawn(move || {
+                        let start = std::time::Instant::now();
+                        while !switched_clone.load(std::sync::atomic::Ordering::SeqCst) {
+                            crate::check_preemption();
+                        }
+                        *slice_clone.lock().unwrap() = start.elapsed();
+                    });
+                crate::ThreadBuilder::new()
+                    .quantum(std::time::Duration::from_millis(2))
+                    .spawn(move || switched.store(true, std::sync::atomic::Ordering::SeqCst));
+            })
+            .unwrap();
+
+        let slice = *slice.lock().unwrap();
+        assert!(slice < std::time::Duration::from_millis(100), "{slice:?}");
+    }
+
+    #[test]
+    fn test_cpu_budget() {
+        let finished = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let finished_clone = std::sync::Arc::clone(&finished);
+
+        let scheduler = crate::Lachesis::builder()
+            .preemption_interval(std::time::Duration::from_millis(1))
+            .build();
+        scheduler
+            .run(move || {
+                // a runaway loop that is terminated once its budget is used up
+                crate::ThreadBuilder::new()
+                    .cpu_budget(crate::CpuBudget::total(std::time::Duration::from_millis(5)))
+                    .spawn(|| {
+                        loop {
+                            crate::check_preemption();
+                        }
+                    });
+
+                crate::ThreadBuilder::new()
+                    .cpu_budget(crate::CpuBudget::rate(
+                        std::time::Duration::from_millis(1),
+                        std::time::Duration::from_millis(4),
+                    ))
+                    .spawn(move || {
+                        let start = std::time::Instant::now();
+                        while start.elapsed() < std::time::Duration::from_millis(10) {
+                            crate::check_preemption();
+                        }
+                        finished_clone.store(true, std::sync::atomic::Ordering::SeqCst);
+                    });
+
+                // a total budget cannot be throttled, it would never refill
+                let throttled = crate::ThreadBuilder::new()
+                    .cpu_budget(
+                        crate::CpuBudget::total(std::time::Duration::from_millis(5))
+                            .policy(crate::BudgetPolicy::Throttle),
+                    )
+                    .try_spawn(|| {});
+                assert!(matches!(throttled, Err(crate::Error::Configuration(_))));
+            })
+            .unwrap();
+
+        assert!(finished.load(std::sync::atomic::Ordering::SeqCst));
+        let errors = crate::take_thread_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], crate::Error::BudgetExceeded { .. }));
+    }
+
+    #[test]
+    fn test_thread_groups() {
+        let shares = std::sync::Arc::new(std::sync::Mutex::new((
+            std::time::Duration::ZERO,
+            std::time::Duration::ZERO,
+        )));
+        let shares_clone = std::sync::Arc::clone(&shares);
+
+        let scheduler = crate::Lachesis::builder()
+            .preemption_interval(std::time::Duration::from_millis(1))
+            .build();
+        scheduler
+            .run(move || {
+                let deadline = std::time::Instant::now() + std::time::Duration::from_millis(60);
+                let busy = move || {
+                    while std::time::Instant::now() < deadline {
+                        crate::check_preemption();
+                    }
+                };
+
+                let crowded = crate::ThreadGroup::new(1024);
+                let sparse = crate::ThreadGroup::new(1024);
+                for _ in 0..10 {
+                    crowded.spawn(busy);
+                }
+                sparse.spawn(busy);
+
+                // nested groups inherit from the spawning thread
+                let doomed = crate::ThreadGroup::new(1024);
+                doomed.spawn(|| {
+                    crate::ThreadGroup::current().child(512).spawn(|| {
+                        loop {
+                            crate::check_preemption();
+                        }
+                    });
+                });
+                doomed.cancel();
+
+                busy();
+                *shares_clone.lock().unwrap() = (crowded.stats().cpu_time, sparse.stats().cpu_time);
+            })
+            .unwrap();
+
+        let (crowded, sparse) = *shares.lock().unwrap();
+        assert!(sparse * 3 > crowded, "{:?} vs {:?}", sparse, crowded);
+
+        let errors = crate::take_thread_errors();
+        assert!(!errors.is_empty());
+        assert!(
+            errors
+                .iter()
+                .all(|e| matches!(e, crate::Error::Cancelled(_)))
+        );
+
+        // a group idle for a while does not catch up by starving the others
+        let progress = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let progress_clone = std::sync::Arc::clone(&progress);
+        crate::Lachesis::builder()
+            .preemption_interval(std::time::Duration::from_millis(1))
+            .build()
+            .run(move || {
+                let idle = crate::ThreadGroup::new(1024);
+                let busy = crate::ThreadGroup::new(1024);
+                let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+                let count = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+                let (done_clone, count_clone) =
+                    (std::sync::Arc::clone(&done), std::sync::Arc::clone(&count));
+                busy.spawn(move || {
+                    while !done_clone.load(std::sync::atomic::Ordering::SeqCst) {
+                        count_clone.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        crate::check_preemption();
+                    }
+                });
+
+                let start = std::time::Instant::now();
+                while start.elapsed() < std::time::Duration::from_millis(40) {
+                    crate::check_preemption();
+                }
+
+                let done_clone = std::sync::Arc::clone(&done);
+                idle.spawn(move || {
+                    let before = count.load(std::sync::atomic::Ordering::SeqCst);
+                    let start = std::time::Instant::now();
+                    while start.elapsed() < std::time::Duration::from_millis(10) {
+                        crate::check_preemption();
+                    }
+                    let during = count.load(std::sync::atomic::Ordering::SeqCst) - before;
+                    progress_clone.store(during, std::sync::atomic::Ordering::SeqCst);
+                    done_clone.store(true, std::sync::atomic::Ordering::SeqCst);
+                });
+                while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                    crate::check_preemption();
+                }
+            })
+            .unwrap();
+        assert!(progress.load(std::sync::atomic::Ordering::SeqCst) > 0);
+    }
+
+    #[test]
+    fn test_yield_to() {
+        let order = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let order_clone = std::sync::Arc::clone(&order);
+
+        let scheduler = crate::Lachesis::builder().build();
+        scheduler
+            .run(move || {
+                let go = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+                let mut ids = Vec::new();
+                for i in 0..3 {
+                    let order = std::sync::Arc::clone(&order_clone);
+                    let go = std::sync::Arc::clone(&go);
+                    ids.push(crate::spawn(
+                        move || {
+                            while !go.load(std::sync::atomic::Ordering::SeqCst) {
+                                crate::runtime::schedule();
+                            }
+                            order.lock().unwrap().push(i);
+                        },
+                        64 * 1024,
+                    ));
+                }
+
+                // hand off to the last waiter, skipping the ring order
+                go.store(true, std::sync::atomic::Ordering::SeqCst);
+                crate::yield_to(ids[2]).unwrap();
+                assert_eq!(order_clone.lock().unwrap()[0], 2);
+
+                while order_clone.lock().unwrap().len() < 3 {
+                    crate::runtime::schedule();
+                }
+                assert!(matches!(
+                    crate::yield_to(ids[0]),
+                    Err(crate::Error::ThreadNotFound(_))
+                ));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_async_preemptible_sections() {
+        let scheduler = crate::Lachesis::builder()
+            .preemption_interval(std::time::Duration::from_millis(1))
+            .async_preemptible_sections(true)
+            .build();
+        scheduler
+            .run(|| {
+                let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+                let stop_clone = std::sync::Arc::clone(&stop);
+
+                // spins without a single checkpoint
+                crate::spawn(
+                    move || {
+                        let x = crate::async_preemptible(|| {
+                            let mut x = 0.5f64;
+                            while !stop_clone.load(std::sync::atomic::Ordering::Relaxed) {
+                                x = std::hint::black_box(x * 1.000001);
+                            }
+                            x
+                        });
+                        assert!(x >= 0.5);
+                    },
+                    64 * 1024,
+                );
+
+                // only reached once the spinning thread has been preempted
+                stop.store(true, std::sync::atomic::Ordering::Relaxed);
+
+                // stdout is locked per OS thread, a tick in the middle of a
+                // println must not let another green thread take it again
+                for i in 0..3 {
+                    crate::spawn(
+                        move || {
+                            let start = std::time::Instant::now();
+                            while start.elapsed() < std::time::Duration::from_millis(20) {
+                                println!("green thread {i}");
+                            }
+                        },
+                        64 * 1024,
+                    );
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_posix_timer_backend() {
+        for clock in [
+            crate::TimerClock::Monotonic,
+            crate::TimerClock::ThreadCpuTime,
+        ] {
+            let scheduler = crate::Lachesis::builder()
+                .preemption_interval(std::time::Duration::from_micros(200))
+                .timer_backend(crate::TimerBackend::Posix(clock))
+                .build();
+            scheduler
+                .run(|| {
+                    let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+                    let stop_clone = std::sync::Arc::clone(&stop);
+                    crate::spawn(
+                        move || {
+                            while !stop_clone.load(std::sync::atomic::Ordering::Relaxed) {
+                                crate::check_preemption();
+                            }
+                        },
+                        64 * 1024,
+                    );
+                    stop.store(true, std::sync::atomic::Ordering::Relaxed);
+                })
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_preemption_signal_chaining() {
+        // a stray signal whose previous action was the default one gets it
+        if std::env::var_os("LACHESIS_SIGNAL_CHILD").is_some() {
+            crate::Lachesis::builder()
+                .preemption_signal(crate::Signal::SIGUSR2)
+                .build()
+                .run(|| nix::sys::signal::raise(crate::Signal::SIGUSR2).unwrap())
+                .unwrap();
+            return;
+        }
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::test_preemption_signal_chaining"])
+            .env("LACHESIS_SIGNAL_CHILD", "1")
+            .output()
+            .unwrap();
+        assert_eq!(
+            std::os::unix::process::ExitStatusExt::signal(&output.status),
+            Some(nix::libc::SIGUSR2)
+        );
+
+        static USER_ALARMS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+        extern "C" fn user_handler(_: i32) {
+            USER_ALARMS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+
+        let user_action = nix::sys::signal::SigAction::new(
+            nix::sys::signal::SigHandler::Handler(user_handler),
+            nix::sys::signal::SaFlags::empty(),
+            nix::sys::signal::SigSet::empty(),
+        );
+        // SIGALRM belongs to the schedulers of the tests running in parallel
+        let original =
+            unsafe { nix::sys::signal::sigaction(crate::Signal::SIGUSR1, &user_action).unwrap() };
+        let original_usr2 =
+            unsafe { nix::sys::signal::sigaction(crate::Signal::SIGUSR2, &user_action).unwrap() };
+
+        let scheduler = crate::Lachesis::builder()
+            .preemption_interval(std::time::Duration::from_millis(1))
+            .preemption_signal(crate::Signal::SIGUSR2)
+            .build();
+        scheduler
+            .run(|| {
+                // SIGUSR1 is left alone
+                nix::sys::signal::raise(crate::Signal::SIGUSR1).unwrap();
+                // a stray SIGUSR2 is not taken for a timer tick, but chained
+                nix::sys::signal::raise(crate::Signal::SIGUSR2).unwrap();
+
+                let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+                let stop_clone = std::sync::Arc::clone(&stop);
+                crate::spawn(
+                    move || {
+                        while !stop_clone.load(std::sync::atomic::Ordering::Relaxed) {
+                            crate::check_preemption();
+                        }
+                    },
+                    64 * 1024,
+                );
+                stop.store(true, std::sync::atomic::Ordering::Relaxed);
+            })
+            .unwrap();
+        assert_eq!(USER_ALARMS.load(std::sync::atomic::Ordering::SeqCst), 2);
+        unsafe { nix::sys::signal::sigaction(crate::Signal::SIGUSR2, &original_usr2).unwrap() };
+
+        // with SIGUSR1, the user's handler is chained and then restored
+        let scheduler = crate::Lachesis::builder()
+            .preemption_signal(crate::Signal::SIGUSR1)
+            .build();
+        scheduler
+            .run(|| nix::sys::signal::raise(crate::Signal::SIGUSR1).unwrap())
+            .unwrap();
+        assert_eq!(USER_ALARMS.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+        nix::sys::signal::raise(crate::Signal::SIGUSR1).unwrap();
+        assert_eq!(USER_ALARMS.load(std::sync::atomic::Ordering::SeqCst), 4);
+
+        unsafe { nix::sys::signal::sigaction(crate::Signal::SIGUSR1, &original).unwrap() };
+    }
+
+    #[test]
+    fn test_preempt_guard() {
+        let scheduler = crate::Lachesis::builder()
+            .preemption_interval(std::time::Duration::from_millis(1))
+            .build();
+        scheduler
+            .run(|| {
+                let ticks = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+                let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+                let ticks_clone = std::sync::Arc::clone(&ticks);
+                let stop_clone = std::sync::Arc::clone(&stop);
+                crate::spawn(
+                    move || {
+                        while !stop_clone.load(std::sync::atomic::Ordering::SeqCst) {
+                            ticks_clone.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                            crate::check_preemption();
+                        }
+                    },
+                    64 * 1024,
+                );
+
+                let before;
+                {
+                    let _outer = crate::PreemptGuard::new();
+                    before = ticks.load(std::sync::atomic::Ordering::SeqCst);
+                    crate::no_preempt(|| {
+                        let start = std::time::Instant::now();
+                        while start.elapsed() < std::time::Duration::from_millis(10) {
+                            crate::check_preemption();
+                        }
+                    });
+                    // the inner section ending does not switch either
+                    crate::check_preemption();
+                    assert_eq!(ticks.load(std::sync::atomic::Ordering::SeqCst), before);
+                }
+
+                // the deferred switch ran when the outer guard was dropped
+                assert!(ticks.load(std::sync::atomic::Ordering::SeqCst) > before);
+                stop.store(true, std::sync::atomic::Ordering::SeqCst);
+            })
+            .unwrap();
+
+        // a spawn or yield inside a critical section switches once it ends,
+        // and the runtime calls in it are no checkpoints when they return
+        crate::Lachesis::builder()
+            .timer_preemption(false)
+            .build()
+            .run(|| {
+                let ran = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+                let ran_clone = std::sync::Arc::clone(&ran);
+                crate::no_preempt(|| {
+                    crate::ThreadBuilder::new().spawn(move || {
+                        ran_clone.store(true, std::sync::atomic::Ordering::SeqCst);
+                    });
+                    crate::runtime::schedule();
+                    let _ = crate::ThreadGroup::current().stats();
+                    let _ = crate::take_thread_errors();
+                    assert!(!ran.load(std::sync::atomic::Ordering::SeqCst));
+                });
+                assert!(ran.load(std::sync::atomic::Ordering::SeqCst));
+            })
+            .unwrap();
+
+        // a guard dropped while unwinding does not switch, the thread could
+        // be cancelled in the meantime and unwind again from the destructor
+        crate::Lachesis::builder()
+            .preemption_interval(std::time::Duration::from_millis(1))
+            .policy(crate::SchedulingPolicy::RoundRobin)
+            .build()
+            .run(|| {
+                let started = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+                let started_clone = std::sync::Arc::clone(&started);
+                let group = crate::ThreadGroup::new(1);
+                group.spawn(move || {
+                    started_clone.store(true, std::sync::atomic::Ordering::SeqCst);
+                    let _ = std::panic::catch_unwind(|| {
+                        let _guard = crate::PreemptGuard::new();
+                        let start = std::time::Instant::now();
+                        while start.elapsed() < std::time::Duration::from_millis(5) {
+                            crate::check_preemption();
+                        }
+                        std::panic::resume_unwind(Box::new(()));
+                    });
+                    loop {
+                        crate::check_preemption();
+                    }
+                });
+                // back here once the guard's thread is switched out
+                while !started.load(std::sync::atomic::Ordering::SeqCst) {
+                    crate::runtime::schedule();
+                }
+                group.cancel();
+            })
+            .unwrap();
+        assert!(matches!(
+            crate::take_thread_errors()[..],
+            [crate::Error::Cancelled(_)]
+        ));
+    }
+
+    // a benchmark: cargo test --lib test_checkpoint_cost -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn test_checkpoint_cost() {
+        const CALLS: u32 = 10_000_000;
+        let costs = std::sync::Arc::new(std::sync::Mutex::new((
+            std::time::Duration::ZERO,
+            std::time::Duration::ZERO,
+        )));
+        let costs_clone = std::sync::Arc::clone(&costs);
+        crate::Lachesis::builder()
+            .timer_preemption(false)
+            .build()
+            .run(move || {
+                let start = std::time::Instant::now();
+                for _ in 0..CALLS {
+                    crate::check_preemption();
+                }
+                let fast = start.elapsed() / CALLS;
+
+                let start = std::time::Instant::now();
+                for _ in 0..CALLS {
+                    crate::timer::checkpoint();
+                }
+                let full = start.elapsed() / CALLS;
+                *costs_clone.lock().unwrap() = (fast, full);
+            })
+            .unwrap();
+
+        let (fast, full) = *costs.lock().unwrap();
+        println!("check_preemption: {fast:?} per call, {full:?} without the fast path");
+        assert!(fast < full, "{fast:?} vs {full:?}");
+    }
+
+    #[test]
+    fn test_checkpoint_budget() {
+        fn run_once() -> Vec<u32> {
+            let log = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let log_clone = std::sync::Arc::clone(&log);
+            let scheduler = crate::Lachesis::builder()
+                .checkpoint_budget(3)
+                .timer_preemption(false)
+                .policy(crate::SchedulingPolicy::RoundRobin)
+                .build();
+            scheduler
+                .run(move || {
+                    for tag in 0..2 {
+                        let log = std::sync::Arc::clone(&log_clone);
+                        crate::spawn(
+                            move || {
+                                for _ in 0..6 {
+                                    log.lock().unwrap().push(tag);
+                                    crate::check_preemption();
+                                }
+                            },
+                            64 * 1024,
+                        );
+                    }
+                })
+                .unwrap();
+            log.lock().unwrap().clone()
+        }
+
+        let first = run_once();
+        assert_eq!(first.len(), 12);
+        assert!(
+            first
+                .chunks(3)
+                .all(|run| run.iter().all(|&tag| tag == run[0]))
+        );
+        assert_eq!(first, run_once());
+
+        let zero = crate::Lachesis::builder().checkpoint_budget(0).build();
+        assert!(zero.run(|| {}).is_err());
+    }
+
+    #[crate::preemptible]
+    fn preemptible_worker(log: std::sync::Arc<std::sync::Mutex<Vec<u32>>>, tag: u32) {
+        for _ in 0..4 {
+            log.lock().unwrap().push(tag);
+        }
+    }
+
+    #[test]
+    fn test_preemptible_macro() {
+        let log = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let hot = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log_clone = std::sync::Arc::clone(&log);
+        let hot_clone = std::sync::Arc::clone(&hot);
+        let scheduler = crate::Lachesis::builder()
+            .checkpoint_budget(2)
+            .timer_preemption(false)
+            .policy(crate::SchedulingPolicy::RoundRobin)
+            .build();
+        scheduler
+            .run(move || {
+                for tag in 0..2 {
+                    let log = std::sync::Arc::clone(&log_clone);
+                    crate::spawn(move || preemptible_worker(log, tag), 64 * 1024);
+                }
+                for tag in 0..2 {
+                    let hot = std::sync::Arc::clone(&hot_clone);
+                    crate::spawn(
+                        crate::preemptible_closure!(move || {
+                            #[no_checkpoint]
+                            for _ in 0..4 {
+                                hot.lock().unwrap().push(tag);
+                            }
+                        }),
+                        64 * 1024,
+                    );
+                }
+            })
+            .unwrap();
+
+        // the entry checkpoint and every second iteration switch threads
+        let log = log.lock().unwrap().clone();
+        assert_eq!(log.len(), 8);
+        assert!(log.windows(2).any(|pair| pair[0] != pair[1]));
+
+        // the opted-out loop runs through in one go
+        let hot = hot.lock().unwrap().clone();
+        assert_eq!(hot, vec![0, 0, 0, 0, 1, 1, 1, 1]);
+    }
+
+    #[crate::test(stack_size = 256 * 1024, preemption_interval = 5, policy = RoundRobin)]
+    fn test_entry_point_macro() {
+        assert_eq!(
+            crate::preemption_interval(),
+            std::time::Duration::from_millis(5)
+        );
+        let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let done_clone = std::sync::Arc::clone(&done);
+        crate::spawn(
+            move || done_clone.store(true, std::sync::atomic::Ordering::SeqCst),
+            64 * 1024,
+        );
+        while !done.load(std::sync::atomic::Ordering::SeqCst) {
+            crate::runtime::schedule();
+        }
+    }
+
+    #[test]
+    fn test_entry_point_propagation() {
+        let scheduler = crate::Lachesis::builder().build();
+        assert_eq!(crate::scheduler::run_entry(scheduler, || 7).unwrap(), 7);
+
+        let scheduler = crate::Lachesis::builder().stack_size(1024).build();
+        assert!(matches!(
+            crate::scheduler::run_entry(scheduler, || ()),
+            Err(crate::Error::InvalidStackSize { .. })
+        ));
+
+        let scheduler = crate::Lachesis::builder().build();
+        let panicked = std::panic::catch_unwind(|| {
+            crate::scheduler::run_entry(scheduler, || panic!("inside a green thread"))
+        });
+        let payload = panicked.unwrap_err();
+        assert_eq!(
+            payload.downcast_ref::<&str>(),
+            Some(&"inside a green thread")
+        );
+    }
+
+    #[test]
+    fn test_deterministic_seed() {
+        fn run_once(seed: u64) -> (Vec<u32>, Vec<crate::ThreadId>) {
+            let scheduler = crate::Lachesis::builder().seed(seed).build();
+            crate::scheduler::run_entry(scheduler, || {
+                let log = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+                let done = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+                let mut ids = Vec::new();
+                for tag in 0..3 {
+                    let log = std::sync::Arc::clone(&log);
+                    let done = std::sync::Arc::clone(&done);
+                    ids.push(crate::spawn(
+                        move || {
+                            for _ in 0..5 {
+                                log.lock().unwrap().push(tag);
+                                crate::check_preemption();
+                            }
+                            done.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        },
+                        64 * 1024,
+                    ));
+                }
+                while done.load(std::sync::atomic::Ordering::SeqCst) < 3 {
+                    crate::check_preemption();
+                }
+                let log = log.lock().unwrap().clone();
+                (log, ids)
+            })
+            .unwrap()
+        }
+
+        let first = run_once(42);
+        assert_eq!(first.0.len(), 15);
+        assert_eq!(first, run_once(42));
+        assert!((0..20).any(|seed| run_once(seed).0 != first.0));
+
+        let (_, report) = crate::Lachesis::builder()
+            .seed(42)
+            .build()
+            .run_with_report(|| {})
+            .unwrap();
+        assert_eq!(report.seed, Some(42));
+
+        // a failing run prints its seed, whether it panics or returns an error
+        if let Some(failure) = std::env::var_os("LACHESIS_SEED_CHILD") {
+            let _ = crate::Lachesis::builder().seed(42).build().run(move || {
+                if failure == "panic" {
+                    panic!("failing run");
+                }
+                crate::ThreadGroup::root().cancel();
+            });
+            return;
+        }
+        for failure in ["panic", "error"] {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "tests::test_deterministic_seed", "--nocapture"])
+                .env("LACHESIS_SEED_CHILD", failure)
+                .env_remove(crate::deterministic::SEED_VAR)
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(stderr.contains("LACHESIS_SEED=42"), "{failure}: {stderr}");
+        }
+    }
+
+    #[crate::test(seed = 7)]
+    fn test_deterministic_entry_point() {
+        assert_eq!(crate::deterministic::seed(), Some(7));
+    }
+
+    #[test]
+    fn test_check_harness() {
+        fn lost_update() {
+            let counter = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let done = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            for _ in 0..2 {
+                let counter = std::sync::Arc::clone(&counter);
+                let done = std::sync::Arc::clone(&done);
+                crate::spawn(
+                    move || {
+                        // a racy read-modify-write with a checkpoint in the middle
+                        let value = counter.load(std::sync::atomic::Ordering::SeqCst);
+                        crate::check_preemption();
+                        counter.store(value + 1, std::sync::atomic::Ordering::SeqCst);
+                        done.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    },
+                    64 * 1024,
+                );
+            }
+            while done.load(std::sync::atomic::Ordering::SeqCst) < 2 {
+                crate::check_preemption();
+            }
+            assert_eq!(counter.load(std::sync::atomic::Ordering::SeqCst), 2);
+        }
+
+        let report = crate::check::Checker::exhaustive(2)
+            .run(lost_update)
+            .unwrap();
+        let failure = report.failure.expect("the lost update is found");
+        assert!(failure.message.contains("assertion"));
+
+        let replayed = std::panic::catch_unwind(|| {
+            crate::check::replay(&failure.trace.to_string(), lost_update)
+        });
+        assert!(replayed.is_err());
+
+        assert_eq!(failure.seed, None);
+
+        let report = crate::check::Checker::pct(200, 2)
+            .seed(1)
+            .run(lost_update)
+            .unwrap();
+        let failure = report.failure.expect("the lost update is found");
+        assert_eq!(failure.seed, Some(1));
+        assert!(failure.to_string().contains("seed 1"));
+
+        // a replay runs on the checker's configuration
+        let checker = crate::check::Checker::exhaustive(0).stack_size(128 * 1024);
+        let small_stack = || assert_eq!(crate::runtime::default_stack_size(), 128 * 1024);
+        let report = checker.run(small_stack).unwrap();
+        assert!(report.failure.is_none());
+        checker.replay("", small_stack);
+        assert!(std::panic::catch_unwind(|| crate::check::replay("", small_stack)).is_err());
+
+        // without a checkpoint in the middle every schedule passes
+        let report = crate::check::Checker::exhaustive(2)
+            .run(|| {
+                let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+                let done_clone = std::sync::Arc::clone(&done);
+                crate::spawn(
+                    move || done_clone.store(true, std::sync::atomic::Ordering::SeqCst),
+                    64 * 1024,
+                );
+                while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                    crate::check_preemption();
+                }
+            })
+            .unwrap();
+        assert!(report.failure.is_none());
+        assert!(report.schedules > 1);
+    }
+
+    #[test]
+    fn test_record_replay() {
+        fn workload(threads: u32) -> impl FnOnce() -> Vec<u32> + Send + 'static {
+            move || {
+                let log = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+                let done = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+                for tag in 0..threads {
+                    let log = std::sync::Arc::clone(&log);
+                    let done = std::sync::Arc::clone(&done);
+                    crate::spawn(
+                        move || {
+                            for _ in 0..100 {
+                                log.lock().unwrap().push(tag);
+                                let start = std::time::Instant::now();
+                                while start.elapsed() < std::time::Duration::from_micros(50) {}
+                                crate::check_preemption();
+                            }
+                            done.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        },
+                        64 * 1024,
+                    );
+                }
+                while done.load(std::sync::atomic::Ordering::SeqCst) < threads {
+                    crate::check_preemption();
+                }
+                log.lock().unwrap().clone()
+            }
+        }
+
+        // a run that dies still leaves the switches it made so far
+        if let Some(path) = std::env::var_os("LACHESIS_RECORD_CHILD") {
+            let recorded = crate::Lachesis::builder()
+                .preemption_interval(std::time::Duration::from_millis(1))
+                .record(path)
+                .build();
+            let _ = recorded.run(|| {
+                workload(3)();
+                std::process::abort();
+            });
+            return;
+        }
+
+        let path = std::env::temp_dir().join(format!("lachesis-{}.switches", std::process::id()));
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::test_record_replay"])
+            .env("LACHESIS_RECORD_CHILD", &path)
+            .output()
+            .unwrap()
+            .status;
+        assert!(!status.success());
+        assert!(!crate::record::load(&path).unwrap().is_empty());
+
+        // a failed run does not leave the scheduler marked as running
+        let missing = crate::Lachesis::builder()
+            .replay(path.with_extension("missing"))
+            .build();
+        for _ in 0..2 {
+            assert!(matches!(
+                missing.run(|| {}),
+                Err(crate::Error::SystemResource { .. })
+            ));
+        }
+
+        let recorded = crate::Lachesis::builder()
+            .preemption_interval(std::time::Duration::from_millis(1))
+            .record(&path)
+            .build();
+        let original = crate::scheduler::run_entry(recorded, workload(3)).unwrap();
+        assert!(original.windows(2).any(|pair| pair[0] != pair[1]));
+
+        let replayed = crate::Lachesis::builder().replay(&path).build();
+        assert_eq!(
+            crate::scheduler::run_entry(replayed, workload(3)).unwrap(),
+            original
+        );
+
+        let diverging = crate::Lachesis::builder().replay(&path).build();
+        assert!(matches!(
+            crate::scheduler::run_entry(diverging, workload(2)),
+            Err(crate::Error::ReplayDiverged(_))
+        ));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_manual_scheduler() {
+        let mut scheduler = crate::ManualScheduler::with_stack_size(64 * 1024).unwrap();
+        let counter = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+
+        // read, checkpoint, write: a lost update if the other thread runs in between
+        let increment = |counter: std::sync::Arc<std::sync::atomic::AtomicU32>| {
+            move || {
+                let seen = counter.load(std::sync::atomic::Ordering::SeqCst);
+                crate::check_preemption();
+                counter.store(seen + 1, std::sync::atomic::Ordering::SeqCst);
+            }
+        };
+        let a = scheduler.spawn(increment(std::sync::Arc::clone(&counter)));
+        let b = scheduler.spawn(increment(std::sync::Arc::clone(&counter)));
+        assert_eq!(scheduler.threads(), vec![a, b]);
+
+        assert_eq!(
+            scheduler.run_until_checkpoint(a).unwrap(),
+            crate::Step::Checkpoint
+        );
+        assert_eq!(
+            scheduler.run_until_checkpoint(b).unwrap(),
+            crate::Step::Checkpoint
+        );
+        assert_eq!(counter.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        assert_eq!(
+            scheduler.run_until_blocked(a).unwrap(),
+            crate::Step::Finished
+        );
+        assert_eq!(counter.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(scheduler.is_finished(a));
+        assert_eq!(scheduler.step().unwrap(), Some((b, crate::Step::Finished)));
+        assert_eq!(counter.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(scheduler.step().unwrap(), None);
+
+        // calls into the runtime are no checkpoints
+        let e = scheduler.spawn(|| {
+            let group = crate::ThreadGroup::current();
+            let _ = group.stats();
+            group.child(2).set_shares(3);
+            let _ = crate::take_thread_errors();
+        });
+        assert_eq!(
+            scheduler.run_until_checkpoint(e).unwrap(),
+            crate::Step::Finished
+        );
+
+        // unfinished threads are cancelled when the scheduler is dropped
+        let dropped = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let dropped_clone = std::sync::Arc::clone(&dropped);
+        let c = scheduler.spawn(move || {
+            struct SetOnDrop(std::sync::Arc<std::sync::atomic::AtomicBool>);
+            impl Drop for SetOnDrop {
+                fn drop(&mut self) {
+                    self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+                }
+            }
+            let _guard = SetOnDrop(dropped_clone);
+            loop {
+                crate::check_preemption();
+            }
+        });
+        assert_eq!(
+            scheduler.run_until_checkpoint(c).unwrap(),
+            crate::Step::Checkpoint
+        );
+        drop(scheduler);
+        assert!(dropped.load(std::sync::atomic::Ordering::SeqCst));
+
+        // a panic in a thread reaches the test
+        let mut scheduler = crate::ManualScheduler::with_stack_size(64 * 1024).unwrap();
+        let d = scheduler.spawn(|| panic!("boom"));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            scheduler.run_until_checkpoint(d)
+        }));
+        assert!(result.is_err());
+        assert!(scheduler.is_finished(d));
+    }
+
+    #[crate::test(stack_size = 256 * 1024, virtual_clock = true)]
+    fn test_virtual_clock() {
+        let start = crate::now();
+        let real_start = std::time::Instant::now();
+        let woken = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        for (tag, minutes) in [(0, 60), (1, 30), (2, 45)] {
+            let woken = std::sync::Arc::clone(&woken);
+            crate::spawn(
+                move || {
+                    crate::sleep(std::time::Duration::from_secs(minutes * 60));
+                    woken.lock().unwrap().push((tag, crate::now() - start));
+                },
+                64 * 1024,
+            );
+        }
+
+        // every thread is asleep, so the clock jumps from deadline to deadline
+        crate::sleep(std::time::Duration::from_secs(2 * 60 * 60));
+        assert_eq!(
+            *woken.lock().unwrap(),
+            vec![
+                (1, std::time::Duration::from_secs(30 * 60)),
+                (2, std::time::Duration::from_secs(45 * 60)),
+                (0, std::time::Duration::from_secs(60 * 60)),
+            ]
+        );
+        assert_eq!(
+            crate::now() - start,
+            std::time::Duration::from_secs(2 * 60 * 60)
+        );
+
+        // a busy thread only sees time move when it is advanced
+        let before = crate::now();
+        crate::advance(std::time::Duration::from_millis(1500)).unwrap();
+        assert_eq!(
+            crate::now() - before,
+            std::time::Duration::from_millis(1500)
+        );
+        assert!(real_start.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_real_clock_cannot_advance() {
+        assert!(crate::advance(std::time::Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn test_run_report() {
+        let scheduler = crate::Lachesis::builder().build();
+        let (sum, report) = scheduler
+            .run_with_report(|| {
+                let total = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+                for i in 1..=4 {
+                    let total = std::sync::Arc::clone(&total);
+                    crate::spawn(
+                        move || {
+                            for _ in 0..3 {
+                                total.fetch_add(i, std::sync::atomic::Ordering::SeqCst);
+                                crate::runtime::schedule();
+                            }
+                        },
+                        64 * 1024,
+                    );
+                }
+                while total.load(std::sync::atomic::Ordering::SeqCst) < 30 {
+                    crate::runtime::schedule();
+                }
+                total.load(std::sync::atomic::Ordering::SeqCst)
+            })
+            .unwrap();
+
+        assert_eq!(sum, 30);
+        assert_eq!(report.threads_spawned, 5);
+        assert_eq!(report.peak_threads, 5);
+        assert_eq!(report.peak_stack_memory, 2 * 1024 * 1024 + 4 * 64 * 1024);
+        assert!(report.switches >= 13);
+        assert!(report.cpu_time <= report.wall_time + std::time::Duration::from_millis(10));
+
+        assert_eq!(
+            crate::Lachesis::builder()
+                .build()
+                .run(|| String::from("done"))
+                .unwrap(),
+            "done"
+        );
+
+        // a main thread dropped without an outcome is not a failed spawn
+        assert!(matches!(
+            crate::scheduler::main_result::<()>(None),
+            Err(crate::Error::Shutdown)
+        ));
+        assert_eq!(crate::scheduler::main_result(Some(Ok(7))).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_shutdown_policy() {
+        struct SetOnDrop(std::sync::Arc<std::sync::atomic::AtomicBool>);
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+
+        // the main thread returning cancels a stray worker, running its destructors
+        let dropped = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let dropped_clone = std::sync::Arc::clone(&dropped);
+        let value = crate::Lachesis::builder()
+            .shutdown_policy(crate::ShutdownPolicy::MainExits)
+            .build()
+            .run(move || {
+                crate::spawn(
+                    move || {
+                        let _guard = SetOnDrop(dropped_clone);
+                        loop {
+                            crate::check_preemption();
+                        }
+                    },
+                    64 * 1024,
+                );
+                crate::runtime::schedule();
+                5
+            })
+            .unwrap();
+        assert_eq!(value, 5);
+        assert!(dropped.load(std::sync::atomic::Ordering::SeqCst));
+        assert!(matches!(
+            crate::take_thread_errors()[..],
+            [crate::Error::Cancelled(_)]
+        ));
+
+        // a sleeping daemon does not keep the scheduler running
+        let start = std::time::Instant::now();
+        crate::Lachesis::builder()
+            .build()
+            .run(|| {
+                crate::ThreadBuilder::new()
+                    .stack_size(64 * 1024)
+                    .daemon(true)
+                    .spawn(|| {
+                        loop {
+                            crate::sleep(std::time::Duration::from_secs(60));
+                        }
+                    });
+            })
+            .unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(crate::take_thread_errors().len(), 1);
+
+        // a thread that refuses to stop is dropped after the timeout
+        let start = std::time::Instant::now();
+        crate::Lachesis::builder()
+            .shutdown_policy(crate::ShutdownPolicy::MainExits)
+            .shutdown_timeout(std::time::Duration::from_millis(20))
+            .build()
+            .run(|| {
+                crate::spawn(
+                    || {
+                        loop {
+                            let _ = std::panic::catch_unwind(|| {
+                                loop {
+                                    crate::check_preemption();
+                                }
+                            });
+                        }
+                    },
+                    64 * 1024,
+                );
+                crate::runtime::schedule();
+            })
+            .unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        assert!(matches!(
+            crate::take_thread_errors()[..],
+            [crate::Error::Cancelled(_)]
+        ));
+    }
+
+    #[test]
+    fn test_try_spawn() {
+        assert!(matches!(
+            crate::try_spawn(|| {}, 64 * 1024),
+            Err(crate::Error::NotInitialized)
+        ));
+
+        crate::Lachesis::builder()
+            .build()
+            .run(|| {
+                assert!(matches!(
+                    crate::try_spawn(|| {}, 1024),
+                    Err(crate::Error::InvalidStackSize { size: 1024, .. })
+                ));
+
+                // more than the address space can hold
+                match crate::try_spawn(|| {}, 1 << 62) {
+                    Err(crate::Error::SystemResource { source, .. }) => {
+                        assert_eq!(source.kind(), std::io::ErrorKind::OutOfMemory)
+                    }
+                    other => panic!("unexpected {other:?}"),
+                }
+
+                let ran = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+                let ran_clone = std::sync::Arc::clone(&ran);
+                let id = crate::try_spawn(
+                    move || ran_clone.store(true, std::sync::atomic::Ordering::SeqCst),
+                    64 * 1024,
+                )
+                .unwrap();
+                assert_ne!(id, 0);
+                while !ran.load(std::sync::atomic::Ordering::SeqCst) {
+                    crate::runtime::schedule();
+                }
+            })
+            .unwrap();
+
+        // the scheduler and the ManualScheduler take the same minimum
+        let min = crate::context::MIN_STACK_SIZE;
+        for size in [min - 1, min] {
+            let scheduler = crate::Lachesis::builder()
+                .stack_size(size)
+                .build()
+                .run(|| {});
+            let manual = crate::ManualScheduler::with_stack_size(size).map(drop);
+            assert_eq!(scheduler.is_ok(), size == min, "{scheduler:?}");
+            assert_eq!(manual.is_ok(), size == min, "{manual:?}");
+        }
+
+        // refused once the scheduler shuts down
+        struct SpawnOnDrop(std::sync::Arc<std::sync::Mutex<Option<crate::Result<u64>>>>);
+        impl Drop for SpawnOnDrop {
+            fn drop(&mut self) {
+                *self.0.lock().unwrap() = Some(crate::try_spawn(|| {}, 64 * 1024));
+            }
+        }
+        let result = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let result_clone = std::sync::Arc::clone(&result);
+        crate::Lachesis::builder()
+            .shutdown_policy(crate::ShutdownPolicy::MainExits)
+            .build()
+            .run(move || {
+                crate::spawn(
+                    move || {
+                        let _guard = SpawnOnDrop(result_clone);
+                        loop {
+                            crate::check_preemption();
+                        }
+                    },
+                    64 * 1024,
+                );
+                crate::runtime::schedule();
+            })
+            .unwrap();
+        assert!(matches!(
+            result.lock().unwrap().take(),
+            Some(Err(crate::Error::SpawnFailed))
+        ));
+        crate::take_thread_errors();
+    }
+
+    #[test]
+    fn test_admission_control() {
+        let (done, report) = crate::Lachesis::builder()
+            .max_threads(3)
+            .build()
+            .run_with_report(|| {
+                let done = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+                for _ in 0..6 {
+                    let done = std::sync::Arc::clone(&done);
+                    crate::spawn_wait(
+                        move || {
+                            for _ in 0..3 {
+                                crate::runtime::schedule();
+                            }
+                            done.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        },
+                        64 * 1024,
+                    )
+                    .unwrap();
+                }
+                assert!(matches!(
+                    crate::try_spawn(|| {}, 64 * 1024),
+                    Err(crate::Error::CapacityExceeded { threads: 3, .. })
+                ));
+                while done.load(std::sync::atomic::Ordering::SeqCst) < 6 {
+                    crate::runtime::schedule();
+                }
+                done.load(std::sync::atomic::Ordering::SeqCst)
+            })
+            .unwrap();
+        assert_eq!(done, 6);
+        assert_eq!(report.threads_spawned, 7);
+        assert_eq!(report.peak_threads, 3);
+
+        crate::Lachesis::builder()
+            .max_stack_memory(2 * 1024 * 1024 + 128 * 1024)
+            .build()
+            .run(|| {
+                let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+                for _ in 0..2 {
+                    let stop = std::sync::Arc::clone(&stop);
+                    crate::try_spawn(
+                        move || {
+                            while !stop.load(std::sync::atomic::Ordering::SeqCst) {
+                                crate::runtime::schedule();
+                            }
+                        },
+                        64 * 1024,
+                    )
+                    .unwrap();
+                }
+                assert!(matches!(
+                    crate::try_spawn(|| {}, 64 * 1024),
+                    Err(crate::Error::CapacityExceeded { threads: 3, .. })
+                ));
+                stop.store(true, std::sync::atomic::Ordering::SeqCst);
+            })
+            .unwrap();
+
+        // nothing can exit to make room
+        crate::Lachesis::builder()
+            .max_threads(1)
+            .build()
+            .run(|| {
+                assert!(matches!(
+                    crate::spawn_wait(|| {}, 64 * 1024),
+                    Err(crate::Error::Deadlock)
+                ));
+            })
+            .unwrap();
+
+        assert!(matches!(
+            crate::Lachesis::builder().max_threads(0).build().run(|| {}),
+            Err(crate::Error::Configuration(_))
+        ));
+    }
+
+    #[test]
+    fn test_stack_pool() {
+        fn run_to_end(stack_size: usize) {
+            let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+            let done_clone = std::sync::Arc::clone(&done);
+            crate::spawn(
+                move || done_clone.store(true, std::sync::atomic::Ordering::SeqCst),
+                stack_size,
+            );
+            while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                crate::runtime::schedule();
+            }
+        }
+
+        crate::Lachesis::builder()
+            .build()
+            .run(|| {
+                // a stack is cached once the scheduler has left it for good
+                run_to_end(64 * 1024);
+                run_to_end(128 * 1024);
+                assert_eq!(crate::cached_stack_bytes(), 64 * 1024);
+
+                // 48KiB is served from the 64KiB class
+                run_to_end(48 * 1024);
+                assert_eq!(crate::cached_stack_bytes(), 128 * 1024);
+
+                crate::trim_stack_pool(0);
+                assert_eq!(crate::cached_stack_bytes(), 0);
+
+                // stacks keep their guard page and work as new when reused
+                for _ in 0..100 {
+                    run_to_end(64 * 1024);
+                }
+                assert_eq!(crate::cached_stack_bytes(), 64 * 1024);
+            })
+            .unwrap();
+
+        crate::Lachesis::builder()
+            .stack_pool_limit(0)
+            .build()
+            .run(|| {
+                for _ in 0..3 {
+                    run_to_end(64 * 1024);
+                }
+                assert_eq!(crate::cached_stack_bytes(), 0);
+            })
+            .unwrap();
+
+        // the pool is unmapped with the scheduler, outside one it is empty
+        crate::trim_stack_pool(0);
+        assert_eq!(crate::cached_stack_bytes(), 0);
+
+        // the main thread's 80KiB stack takes a 128KiB class
+        assert!(matches!(
+            crate::Lachesis::builder()
+                .stack_size(80 * 1024)
+                .max_stack_memory(96 * 1024)
+                .build()
+                .run(|| {}),
+            Err(crate::Error::Configuration(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_lazily_committed_stacks() {
+        fn resident_bytes() -> usize {
+            let statm = std::fs::read_to_string("/proc/self/statm").unwrap();
+            let pages: usize = statm.split_whitespace().nth(1).unwrap().parse().unwrap();
+            pages * 4096
+        }
+
+        let before = resident_bytes();
+        crate::Lachesis::builder()
+            .stack_guard_size(64 * 1024)
+            .build()
+            .run(move || {
+                // 8GiB of stacks, of which only the top pages are touched
+                for _ in 0..2000 {
+                    crate::ThreadBuilder::new()
+                        .stack_size(4 * 1024 * 1024)
+                        .daemon(true)
+                        .spawn(|| crate::sleep(std::time::Duration::from_secs(3600)));
+                }
+                assert!(resident_bytes().saturating_sub(before) < 512 * 1024 * 1024);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // the overflow aborts, so it runs in a copy of this test binary
+        if std::env::var_os("LACHESIS_OVERFLOW_CHILD").is_some() {
+            fn recurse(depth: u64) -> u64 {
+                if depth == u64::MAX {
+                    return 0;
+                }
+                let frame = std::hint::black_box([depth as u8; 1024]);
+                recurse(depth + 1) + frame[0] as u64
+            }
+
+            crate::Lachesis::builder()
+                .build()
+                .run(|| {
+                    crate::ThreadBuilder::new()
+                        .name("parser")
+                        .stack_size(64 * 1024)
+                        .spawn(|| {
+                            recurse(0);
+                        });
+                })
+                .unwrap();
+            return;
+        }
+
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::test_stack_overflow", "--nocapture"])
+            .env("LACHESIS_OVERFLOW_CHILD", "1")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success());
+        assert!(
+            stderr.contains("/parser overflowed its 65536 byte stack"),
+            "{stderr}"
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            std::os::unix::process::ExitStatusExt::signal(&output.status),
+            Some(nix::libc::SIGABRT)
+        );
+    }
+
+    #[test]
+    fn test_handle() {
+        fn assert_send_sync<T: Send + Sync + Clone>(_: &T) {}
+
+        let scheduler = crate::Lachesis::builder().build();
+        let handle = scheduler.handle();
+        assert_send_sync(&handle);
+
+        let ran = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        // queued before the run, started once it begins
+        let ran_clone = std::sync::Arc::clone(&ran);
+        handle.spawn(move || ran_clone.lock().unwrap().push(("early", crate::now())));
+
+        let start = std::time::Instant::now();
+        let ran_clone = std::sync::Arc::clone(&ran);
+        let injector = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            for _ in 0..3 {
+                let ran = std::sync::Arc::clone(&ran_clone);
+                handle.spawn_with_stack_size(
+                    move || ran.lock().unwrap().push(("injected", crate::now())),
+                    64 * 1024,
+                );
+            }
+        });
+
+        // every green thread is asleep, the scheduler is woken by the handle
+        scheduler
+            .run(|| crate::sleep(std::time::Duration::from_millis(500)))
+            .unwrap();
+        injector.join().unwrap();
+
+        let ran = ran.lock().unwrap();
+        assert_eq!(
+            ran.iter().map(|(tag, _)| *tag).collect::<Vec<_>>(),
+            ["early", "injected", "injected", "injected"]
+        );
+        for (_, at) in &ran[1..] {
+            assert!(*at - start < std::time::Duration::from_millis(400));
+        }
+    }
+
+    #[test]
+    fn test_independent_schedulers() {
+        let run = |threads: u64| {
+            let total = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+            let total_clone = std::sync::Arc::clone(&total);
+            crate::Lachesis::builder()
+                .preemption_interval(std::time::Duration::from_millis(1))
+                .build()
+                .run(move || {
+                    for i in 1..=threads {
+                        let total = std::sync::Arc::clone(&total_clone);
+                        crate::spawn(
+                            move || {
+                                for _ in 0..100 {
+                                    total.fetch_add(i, std::sync::atomic::Ordering::Relaxed);
+                                    crate::check_preemption();
+                                }
+                            },
+                            64 * 1024,
+                        );
+                    }
+                })
+                .unwrap();
+            total.load(std::sync::atomic::Ordering::Relaxed)
+        };
+
+        // each OS thread has its own runtime, and runs it twice
+        let handles: Vec<_> = (1..=4)
+            .map(|threads| std::thread::spawn(move || (run(threads), run(threads))))
+            .collect();
+        for (threads, handle) in (1..=4u64).zip(handles) {
+            let expected = 100 * threads * (threads + 1) / 2;
+            assert_eq!(handle.join().unwrap(), (expected, expected));
+        }
+
+        // a scheduler cannot start while another is running on the same OS thread
+        crate::Lachesis::builder()
+            .build()
+            .run(|| {
+                let nested = crate::Lachesis::builder().build().run(|| {});
+                assert!(matches!(
+                    nested,
+                    Err(crate::error::Error::AlreadyInitialized)
+                ));
+            })
+            .unwrap();
     }
 }
@@ -1,27 +1,27 @@
+// safe points are inserted at each iteration of the outer loops
+#[lachesis::preemptible]
 fn worker_thread_1() {
     println!("Worker thread 1 starting");
     for i in 0..10 {
         println!("Worker 1: {}", i);
 
+        #[no_checkpoint]
         for j in 0..1000000 {
             std::hint::black_box(i * j);
         }
-
-        // Regular safe point after each iteration
-        lachesis::check_preemption();
     }
 }
 
+#[lachesis::preemptible]
 fn worker_thread_2() {
     println!("Worker thread 2 starting");
     for i in 0..10 {
         println!("Worker 2: {}", i);
 
+        #[no_checkpoint]
         for j in 0..800000 {
             std::hint::black_box(i * j);
         }
-
-        lachesis::check_preemption();
     }
 }
 
@@ -120,7 +120,7 @@ fn main() {
 
     let scheduler = lachesis::Lachesis::builder()
         .stack_size(4 * 1024 * 1024) // 4MB
-        .preemption_interval(10)
+        .preemption_interval(std::time::Duration::from_millis(10))
         .build();
 
     if let Err(e) = scheduler.run(main_green_thread) {
@@ -0,0 +1,131 @@
+// a scheduler for tests that decide themselves which thread runs and how far;
+// every thread stays suspended until the test resumes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    // stopped at a check_preemption call
+    Checkpoint,
+    // cannot run on: asleep or throttled by its CPU budget
+    Blocked,
+    Finished,
+}
+
+pub struct ManualScheduler {
+    outer_depth: usize,
+    // threads in the order step() runs them
+    order: std::collections::VecDeque<crate::types::ThreadId>,
+    _entered: crate::runtime::Entered,
+}
+
+impl ManualScheduler {
+    pub fn new() -> crate::error::Result<Self> {
+        Self::with_stack_size(2 * 1024 * 1024)
+    }
+
+    pub fn with_stack_size(stack_size: usize) -> crate::error::Result<Self> {
+        if stack_size < crate::context::MIN_STACK_SIZE {
+            return Err(crate::error::Error::InvalidStackSize {
+                size: stack_size,
+                min: crate::context::MIN_STACK_SIZE,
+            });
+        }
+
+        // fails inside another scheduler on this OS thread
+        let entered = crate::runtime::enter()?;
+        let outer_depth = unsafe { crate::runtime::manual_begin(stack_size) };
+        Ok(ManualScheduler {
+            outer_depth,
+            order: std::collections::VecDeque::new(),
+            _entered: entered,
+        })
+    }
+
+    // the thread does not start until it is run
+    pub fn spawn<F>(&mut self, func: F) -> crate::types::ThreadId
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let id = crate::thread::ThreadBuilder::new().spawn(func);
+        self.sync_order();
+        id
+    }
+
+    pub fn threads(&self) -> Vec<crate::types::ThreadId> {
+        crate::runtime::thread_ids()
+    }
+
+    pub fn is_finished(&self, id: crate::types::ThreadId) -> bool {
+        crate::runtime::thread_state(id).is_none()
+    }
+
+    // run one thread up to its next checkpoint
+    pub fn run_until_checkpoint(
+        &mut self,
+        id: crate::types::ThreadId,
+    ) -> crate::error::Result<Step> {
+        let finished = unsafe { crate::runtime::manual_resume(id)? };
+        self.sync_order();
+
+        if finished {
+            return Ok(Step::Finished);
+        }
+        match crate::runtime::thread_state(id) {
+            Some(
+                crate::ThreadState::Throttled
+                | crate::ThreadState::Sleeping
+                | crate::ThreadState::WaitingForCapacity,
+            ) => Ok(Step::Blocked),
+            _ => Ok(Step::Checkpoint),
+        }
+    }
+
+    // run one thread through its checkpoints until it cannot go on; with no
+    // blocking primitives in the runtime that is when it is throttled or done
+    pub fn run_until_blocked(&mut self, id: crate::types::ThreadId) -> crate::error::Result<Step> {
+        loop {
+            match self.run_until_checkpoint(id)? {
+                Step::Checkpoint => continue,
+                step => return Ok(step),
+            }
+        }
+    }
+
+    // run the thread that has waited longest up to its next checkpoint;
+    // None once every thread has finished
+    pub fn step(&mut self) -> crate::error::Result<Option<(crate::types::ThreadId, Step)>> {
+        self.sync_order();
+        let Some(id) = self.order.pop_front() else {
+            return Ok(None);
+        };
+        self.order.push_back(id);
+        let step = self.run_until_checkpoint(id)?;
+        Ok(Some((id, step)))
+    }
+
+    // step until every thread has finished
+    pub fn run_to_completion(&mut self) -> crate::error::Result<()> {
+        while self.step()?.is_some() {}
+        Ok(())
+    }
+
+    // keep the step order in line with the threads that exist
+    fn sync_order(&mut self) {
+        let live = crate::runtime::thread_ids();
+        self.order.retain(|id| live.contains(id));
+        for id in live {
+            if !self.order.contains(&id) {
+                self.order.push_back(id);
+            }
+        }
+    }
+}
+
+impl Drop for ManualScheduler {
+    // unfinished threads are cancelled and unwound
+    fn drop(&mut self) {
+        while let Some(&id) = crate::runtime::thread_ids().first() {
+            crate::runtime::cancel_thread(id);
+            let _ = std::panic::catch_unwind(|| unsafe { crate::runtime::manual_resume(id) });
+        }
+        unsafe { crate::runtime::manual_end(self.outer_depth) };
+    }
+}
@@ -0,0 +1,200 @@
+// a green thread running into the guard region below its stack is reported
+// and the process aborted; the fault is handled on an alternate signal stack,
+// as the thread's own stack is exhausted, and any other fault is passed on to
+// the handler that was there before
+
+// like the preemption handler, installed by the first running scheduler and
+// given back by the last one
+static USERS: std::sync::Mutex<usize> = std::sync::Mutex::new(0);
+// the SIGSEGV and SIGBUS handlers from before ours, only written under USERS
+static mut PREVIOUS_ACTIONS: [Option<nix::sys::signal::SigAction>; 2] = [None; 2];
+
+const SIGNALS: [nix::sys::signal::Signal; 2] = [
+    nix::sys::signal::Signal::SIGSEGV,
+    nix::sys::signal::Signal::SIGBUS,
+];
+const ALT_STACK_SIZE: usize = 64 * 1024;
+
+// the handler, and an alternate stack for this OS thread unless it has one
+pub struct Installed {
+    alt_stack: Option<std::ptr::NonNull<std::ffi::c_void>>,
+}
+
+pub fn install() -> Installed {
+    {
+        let mut users = USERS.lock().unwrap_or_else(|e| e.into_inner());
+        *users += 1;
+        if *users == 1 {
+            let action = nix::sys::signal::SigAction::new(
+                nix::sys::signal::SigHandler::SigAction(overflow_handler),
+                nix::sys::signal::SaFlags::SA_SIGINFO | nix::sys::signal::SaFlags::SA_ONSTACK,
+                nix::sys::signal::SigSet::empty(),
+            );
+            unsafe {
+                let previous_ptr = &raw mut PREVIOUS_ACTIONS;
+                for (index, signal) in SIGNALS.into_iter().enumerate() {
+                    (*previous_ptr)[index] = nix::sys::signal::sigaction(signal, &action).ok();
+                }
+            }
+        }
+    }
+
+    Installed {
+        alt_stack: unsafe { ensure_alt_stack() },
+    }
+}
+
+impl Drop for Installed {
+    fn drop(&mut self) {
+        if let Some(alt_stack) = self.alt_stack.take() {
+            unsafe {
+                let disable = nix::libc::stack_t {
+                    ss_sp: std::ptr::null_mut(),
+                    ss_flags: nix::libc::SS_DISABLE,
+                    ss_size: 0,
+                };
+                nix::libc::sigaltstack(&disable, std::ptr::null_mut());
+                let _ = nix::sys::mman::munmap(alt_stack, ALT_STACK_SIZE);
+            }
+        }
+
+        let mut users = USERS.lock().unwrap_or_else(|e| e.into_inner());
+        *users -= 1;
+        if *users > 0 {
+            return;
+        }
+        unsafe {
+            let previous_ptr = &raw mut PREVIOUS_ACTIONS;
+            for (index, signal) in SIGNALS.into_iter().enumerate() {
+                if let Some(previous) = (*previous_ptr)[index].take() {
+                    let _ = nix::sys::signal::sigaction(signal, &previous);
+                }
+            }
+        }
+    }
+}
+
+// Rust sets one up for the threads it starts; returns the stack if it had to
+// map one of its own
+unsafe fn ensure_alt_stack() -> Option<std::ptr::NonNull<std::ffi::c_void>> {
+    unsafe {
+        let mut current: nix::libc::stack_t = std::mem::zeroed();
+        if nix::libc::sigaltstack(std::ptr::null(), &mut current) != 0
+            || current.ss_flags & nix::libc::SS_DISABLE == 0
+        {
+            return None;
+        }
+
+        let mapping = nix::sys::mman::mmap_anonymous(
+            None,
+            std::num::NonZeroUsize::new(ALT_STACK_SIZE)?,
+            nix::sys::mman::ProtFlags::PROT_READ | nix::sys::mman::ProtFlags::PROT_WRITE,
+            nix::sys::mman::MapFlags::MAP_PRIVATE | nix::sys::mman::MapFlags::MAP_ANONYMOUS,
+        )
+        .ok()?;
+        let alt_stack = nix::libc::stack_t {
+            ss_sp: mapping.as_ptr(),
+            ss_flags: 0,
+            ss_size: ALT_STACK_SIZE,
+        };
+        if nix::libc::sigaltstack(&alt_stack, std::ptr::null_mut()) != 0 {
+            let _ = nix::sys::mman::munmap(mapping, ALT_STACK_SIZE);
+            return None;
+        }
+        Some(mapping)
+    }
+}
+
+extern "C" fn overflow_handler(
+    signal: i32,
+    info: *mut nix::libc::siginfo_t,
+    ucontext: *mut std::ffi::c_void,
+) {
+    unsafe {
+        let address = fault_address(info);
+        let runtime = crate::runtime::current();
+        if !runtime.is_null() {
+            let contexts_ptr = &raw const (*runtime).contexts;
+            if let Some(ctx) = (*contexts_ptr)
+                .iter()
+                .find(|c| c.stack.guard_contains(address))
+            {
+                report(ctx);
+                std::process::abort();
+            }
+        }
+        chain_previous(signal, info, ucontext);
+    }
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn fault_address(info: *mut nix::libc::siginfo_t) -> usize {
+    unsafe { (*info).si_addr() as usize }
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn fault_address(info: *mut nix::libc::siginfo_t) -> usize {
+    unsafe { (*info).si_addr as usize }
+}
+
+// formatted into a fixed buffer, nothing may allocate here
+fn report(ctx: &crate::context::Context) {
+    struct Buffer {
+        bytes: [u8; 512],
+        len: usize,
+    }
+
+    impl std::fmt::Write for Buffer {
+        fn write_str(&mut self, s: &str) -> std::fmt::Result {
+            let n = s.len().min(self.bytes.len() - self.len);
+            self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+            self.len += n;
+            Ok(())
+        }
+    }
+
+    let mut buffer = Buffer {
+        bytes: [0; 512],
+        len: 0,
+    };
+    let _ = std::fmt::Write::write_fmt(
+        &mut buffer,
+        format_args!(
+            "green thread {}/{} overflowed its {} byte stack\n",
+            ctx.id,
+            ctx.name.as_deref().unwrap_or("<unnamed>"),
+            ctx.stack_size
+        ),
+    );
+    unsafe { nix::libc::write(2, buffer.bytes.as_ptr().cast(), buffer.len) };
+}
+
+// not a stack overflow of ours; with no handler before us the default action
+// is restored, so the fault kills the process when the instruction runs again
+unsafe fn chain_previous(
+    signal: i32,
+    info: *mut nix::libc::siginfo_t,
+    ucontext: *mut std::ffi::c_void,
+) {
+    unsafe {
+        let previous_ptr = &raw const PREVIOUS_ACTIONS;
+        let index = SIGNALS.iter().position(|s| *s as i32 == signal);
+        let previous = index.and_then(|i| (*previous_ptr)[i]);
+        match previous.map(|a| a.handler()) {
+            Some(nix::sys::signal::SigHandler::Handler(handler)) => handler(signal),
+            Some(nix::sys::signal::SigHandler::SigAction(handler)) => {
+                handler(signal, info, ucontext)
+            }
+            _ => {
+                let default = nix::sys::signal::SigAction::new(
+                    nix::sys::signal::SigHandler::SigDfl,
+                    nix::sys::signal::SaFlags::empty(),
+                    nix::sys::signal::SigSet::empty(),
+                );
+                if let Ok(signal) = nix::sys::signal::Signal::try_from(signal) {
+                    let _ = nix::sys::signal::sigaction(signal, &default);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+thread_local! {
+    // depth of the sections that must not be preempted asynchronously:
+    // runtime internals, allocator calls and no_preempt closures
+    static DEPTH: std::sync::atomic::AtomicUsize = const { std::sync::atomic::AtomicUsize::new(0) };
+}
+
+static ALLOCATOR_GUARDED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+pub fn disable() {
+    DEPTH.with(|d| d.fetch_add(1, std::sync::atomic::Ordering::SeqCst));
+}
+
+pub fn enable() {
+    DEPTH.with(|d| d.fetch_sub(1, std::sync::atomic::Ordering::SeqCst));
+}
+
+pub fn depth() -> usize {
+    DEPTH.with(|d| d.load(std::sync::atomic::Ordering::SeqCst))
+}
+
+// the depth is part of a green thread's state and is swapped on every switch
+pub fn set_depth(depth: usize) {
+    DEPTH.with(|d| d.store(depth, std::sync::atomic::Ordering::SeqCst));
+}
+
+// while held, the calling green thread is not switched out: check_preemption
+// and the timer signal defer the switch until the outermost guard is dropped
+pub struct PreemptGuard {
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+impl Default for PreemptGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PreemptGuard {
+    pub fn new() -> Self {
+        disable();
+        PreemptGuard {
+            _not_send: std::marker::PhantomData,
+        }
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        enable();
+        // run the switch that was deferred while the guard was held, unless
+        // the thread is unwinding: a cancellation picked up while switched out
+        // would unwind again from this destructor, and the panic count of the
+        // OS thread would carry over to the other green threads
+        if depth() == 0 && !std::thread::panicking() && !crate::runtime::run_deferred_switch() {
+            crate::timer::check_preemption();
+        }
+    }
+}
+
+// run f as a runtime internal: the timer signal does not switch away from it,
+// and unlike no_preempt its end is not a checkpoint, so calling into the
+// runtime never switches threads by itself
+pub fn internal<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    struct Internal;
+
+    impl Drop for Internal {
+        fn drop(&mut self) {
+            enable();
+        }
+    }
+
+    disable();
+    let _internal = Internal;
+    f()
+}
+
+// run f as a critical section, e.g. while holding a std lock another green
+// thread may also take
+pub fn no_preempt<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _guard = PreemptGuard::new();
+    f()
+}
+
+// run f as a section the timer signal may switch away from at any instruction
+// when async_preemptible_sections is on; everywhere else a tick only asks for a switch at
+// the next checkpoint. std locks belong to the OS thread, so f must not hold
+// any of them across a tick, including the ones behind println! and the other
+// std I/O handles
+pub fn async_preemptible<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    struct Section;
+
+    impl Drop for Section {
+        fn drop(&mut self) {
+            crate::runtime::change_async_sections(false);
+        }
+    }
+
+    crate::runtime::change_async_sections(true);
+    let _section = Section;
+    f()
+}
+
+pub fn allocator_guarded() -> bool {
+    drop(std::hint::black_box(Box::new(0u8)));
+    ALLOCATOR_GUARDED.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+// global allocator wrapper that keeps allocator calls from being preempted,
+// required for async_preemptible_sections:
+//
+// #[global_allocator]
+// static ALLOC: lachesis::PreemptSafeAlloc<std::alloc::System> =
+//     lachesis::PreemptSafeAlloc(std::alloc::System);
+pub struct PreemptSafeAlloc<A>(pub A);
+
+unsafe impl<A: std::alloc::GlobalAlloc> std::alloc::GlobalAlloc for PreemptSafeAlloc<A> {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        ALLOCATOR_GUARDED.store(true, std::sync::atomic::Ordering::Relaxed);
+        disable();
+        let ptr = unsafe { self.0.alloc(layout) };
+        enable();
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        disable();
+        unsafe { self.0.dealloc(ptr, layout) };
+        enable();
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: std::alloc::Layout) -> *mut u8 {
+        ALLOCATOR_GUARDED.store(true, std::sync::atomic::Ordering::Relaxed);
+        disable();
+        let ptr = unsafe { self.0.alloc_zeroed(layout) };
+        enable();
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: std::alloc::Layout, new_size: usize) -> *mut u8 {
+        disable();
+        let ptr = unsafe { self.0.realloc(ptr, layout, new_size) };
+        enable();
+        ptr
+    }
+}
@@ -0,0 +1,317 @@
+// recording of context switches and replaying them in a later run; threads
+// are named by the order they were spawned in, since their ids are random
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    // a check_preemption call
+    Checkpoint,
+    Spawn,
+    Yield,
+    YieldTo,
+    Sleep,
+    // spawn_wait waiting for a thread to exit
+    Capacity,
+    // a switch from the timer signal inside an async_preemptible section,
+    // which cannot be replayed
+    Signal,
+    Exit,
+}
+
+impl Reason {
+    fn name(self) -> &'static str {
+        match self {
+            Reason::Checkpoint => "checkpoint",
+            Reason::Spawn => "spawn",
+            Reason::Yield => "yield",
+            Reason::YieldTo => "yield_to",
+            Reason::Sleep => "sleep",
+            Reason::Capacity => "capacity",
+            Reason::Signal => "signal",
+            Reason::Exit => "exit",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        [
+            Reason::Checkpoint,
+            Reason::Spawn,
+            Reason::Yield,
+            Reason::YieldTo,
+            Reason::Sleep,
+            Reason::Capacity,
+            Reason::Signal,
+            Reason::Exit,
+        ]
+        .into_iter()
+        .find(|r| r.name() == name)
+    }
+}
+
+// `from` switched out at its `checkpoints`-th checkpoint and `to` was switched in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Switch {
+    pub from: u64,
+    pub reason: Reason,
+    pub checkpoints: u64,
+    pub to: u64,
+}
+
+impl std::fmt::Display for Switch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.from,
+            self.reason.name(),
+            self.checkpoints,
+            self.to
+        )
+    }
+}
+
+const HEADER: &str = "lachesis switches 1";
+
+struct Threads {
+    ordinals: std::collections::HashMap<crate::types::ThreadId, u64>,
+    checkpoints: std::collections::HashMap<crate::types::ThreadId, u64>,
+    next: u64,
+}
+
+struct Replaying {
+    switches: Vec<Switch>,
+    position: usize,
+    diverged: Option<String>,
+}
+
+// the switch log, written as the run goes so that a run that crashes still
+// leaves its switches behind
+pub struct Recording {
+    path: std::path::PathBuf,
+    file: std::fs::File,
+    failed: Option<std::io::Error>,
+}
+
+// the recording or replay of one runtime
+#[derive(Default)]
+pub struct State {
+    threads: Option<Threads>,
+    recording: Option<Recording>,
+    replaying: Option<Replaying>,
+}
+
+fn state() -> *mut State {
+    let runtime = crate::runtime::current();
+    if runtime.is_null() {
+        return std::ptr::null_mut();
+    }
+    unsafe { &raw mut (*runtime).record }
+}
+
+// truncates the log and writes its header, before the run starts
+pub fn create(path: &std::path::Path) -> crate::error::Result<Recording> {
+    let write_error = |source| crate::error::Error::SystemResource {
+        what: format!("cannot write {}", path.display()),
+        source,
+    };
+    let mut file = std::fs::File::create(path).map_err(write_error)?;
+    std::io::Write::write_all(&mut file, format!("{HEADER}\n").as_bytes()).map_err(write_error)?;
+    Ok(Recording {
+        path: path.to_path_buf(),
+        file,
+        failed: None,
+    })
+}
+
+pub fn start(recording: Option<Recording>, replay: Option<Vec<Switch>>) {
+    unsafe {
+        (*crate::runtime::runtime()).record = State {
+            threads: (recording.is_some() || replay.is_some()).then(|| Threads {
+                ordinals: std::collections::HashMap::new(),
+                checkpoints: std::collections::HashMap::new(),
+                next: 0,
+            }),
+            recording,
+            replaying: replay.map(|switches| Replaying {
+                switches,
+                position: 0,
+                diverged: None,
+            }),
+        };
+    }
+}
+
+// reports a recording that could not be written and a replay that went its
+// own way
+pub fn finish() -> crate::error::Result<()> {
+    let state = unsafe { std::mem::take(&mut (*crate::runtime::runtime()).record) };
+    if let Some(replaying) = state.replaying
+        && let Some(divergence) = replaying.diverged
+    {
+        return Err(crate::error::Error::ReplayDiverged(divergence));
+    }
+
+    if let Some(recording) = state.recording
+        && let Some(source) = recording.failed
+    {
+        return Err(crate::error::Error::SystemResource {
+            what: format!("cannot write {}", recording.path.display()),
+            source,
+        });
+    }
+    Ok(())
+}
+
+pub fn load(path: &std::path::Path) -> crate::error::Result<Vec<Switch>> {
+    let log =
+        std::fs::read_to_string(path).map_err(|source| crate::error::Error::SystemResource {
+            what: format!("cannot read {}", path.display()),
+            source,
+        })?;
+    let invalid = |line: &str| {
+        crate::error::Error::Configuration(format!(
+            "{} is not a switch log: {line:?}",
+            path.display()
+        ))
+    };
+
+    let mut lines = log.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(invalid(log.lines().next().unwrap_or("")));
+    }
+
+    lines
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [from, reason, checkpoints, to] = fields[..] else {
+                return Err(invalid(line));
+            };
+            Ok(Switch {
+                from: from.parse().map_err(|_| invalid(line))?,
+                reason: Reason::parse(reason).ok_or_else(|| invalid(line))?,
+                checkpoints: checkpoints.parse().map_err(|_| invalid(line))?,
+                to: to.parse().map_err(|_| invalid(line))?,
+            })
+        })
+        .collect()
+}
+
+fn threads() -> Option<&'static mut Threads> {
+    unsafe { state().as_mut()?.threads.as_mut() }
+}
+
+fn ordinal(threads: &Threads, id: crate::types::ThreadId) -> u64 {
+    threads.ordinals.get(&id).copied().unwrap_or(u64::MAX)
+}
+
+pub fn on_spawn(id: crate::types::ThreadId) {
+    if let Some(threads) = threads() {
+        threads.ordinals.insert(id, threads.next);
+        threads.next += 1;
+    }
+}
+
+// whether the run is recorded or replayed
+pub fn is_active() -> bool {
+    threads().is_some()
+}
+
+pub fn on_checkpoint(id: crate::types::ThreadId) {
+    if let Some(threads) = threads() {
+        *threads.checkpoints.entry(id).or_insert(0) += 1;
+    }
+}
+
+pub fn on_switch(from: crate::types::ThreadId, reason: Reason, to: crate::types::ThreadId) {
+    let Some(threads) = threads() else {
+        return;
+    };
+    let switch = Switch {
+        from: ordinal(threads, from),
+        reason,
+        checkpoints: threads.checkpoints.get(&from).copied().unwrap_or(0),
+        to: ordinal(threads, to),
+    };
+
+    unsafe {
+        let state = state();
+        // one write per line, so a crash loses at most the switch being written
+        if let Some(recording) = (*state).recording.as_mut()
+            && recording.failed.is_none()
+            && let Err(e) =
+                std::io::Write::write_all(&mut recording.file, format!("{switch}\n").as_bytes())
+        {
+            recording.failed = Some(e);
+        }
+
+        if let Some(replaying) = (*state).replaying.as_mut()
+            && replaying.diverged.is_none()
+        {
+            let expected = replaying.switches.get(replaying.position);
+            if expected != Some(&switch) {
+                replaying.diverged = Some(match expected {
+                    Some(expected) => format!(
+                        "switch {} was `{switch}`, the recording has `{expected}`",
+                        replaying.position
+                    ),
+                    None => format!(
+                        "switch {} was `{switch}`, past the end of the recording",
+                        replaying.position
+                    ),
+                });
+            }
+            replaying.position += 1;
+        }
+    }
+}
+
+fn diverge(message: String) {
+    unsafe {
+        if let Some(replaying) = state().as_mut().and_then(|s| s.replaying.as_mut()) {
+            replaying.diverged.get_or_insert(message);
+        }
+    }
+}
+
+fn expected() -> Option<Switch> {
+    unsafe {
+        let replaying = state().as_ref()?.replaying.as_ref()?;
+        if replaying.diverged.is_some() {
+            return None;
+        }
+        replaying.switches.get(replaying.position).copied()
+    }
+}
+
+// forces the recorded switches; once the run has diverged or gone past the
+// end of the recording it runs round-robin, yielding at every checkpoint
+pub struct Replay;
+
+impl crate::deterministic::Strategy for Replay {
+    fn pick(&mut self, ready: &[crate::types::ThreadId]) -> usize {
+        let (Some(expected), Some(threads)) = (expected(), threads()) else {
+            return 0;
+        };
+        ready
+            .iter()
+            .position(|&id| ordinal(threads, id) == expected.to)
+            .unwrap_or(0)
+    }
+
+    fn should_yield(&mut self, running: crate::types::ThreadId) -> bool {
+        let (Some(expected), Some(threads)) = (expected(), threads()) else {
+            return true;
+        };
+        let from = ordinal(threads, running);
+        let checkpoints = threads.checkpoints.get(&running).copied().unwrap_or(0);
+
+        // the recorded switch should have happened by now
+        if from != expected.from || checkpoints > expected.checkpoints {
+            diverge(format!(
+                "thread {from} ran to checkpoint {checkpoints}, the recording has `{expected}` next"
+            ));
+            return true;
+        }
+
+        expected.reason == Reason::Checkpoint && checkpoints == expected.checkpoints
+    }
+}
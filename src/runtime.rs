@@ -1,183 +1,1050 @@
-pub static mut CTX_MAIN: std::option::Option<std::boxed::Box<crate::context::Registers>> =
-    std::option::Option::None;
-pub static mut UNUSED_STACK: (*mut u8, std::alloc::Layout) =
-    (std::ptr::null_mut(), std::alloc::Layout::new::<u8>());
-pub static mut CONTEXTS: std::collections::LinkedList<std::boxed::Box<crate::context::Context>> =
-    std::collections::LinkedList::new();
-pub static mut ID: *mut std::collections::HashSet<u64> = std::ptr::null_mut();
-pub static mut CURRENT_THREAD_ID: u64 = 0;
+// the state of one scheduler; every run has its own, reached through the
+// CURRENT pointer of the OS thread it runs on
+pub struct Runtime {
+    pub ctx_main: Option<Box<crate::context::Registers>>,
+    pub contexts: std::collections::VecDeque<Box<crate::context::Context>>,
+    pub ids: std::collections::HashSet<u64>,
+    pub current_thread_id: u64,
+    pub default_stack_size: usize,
+    pub switched_in_at: Option<std::time::Instant>,
+    pub thread_errors: Vec<crate::error::Error>,
+    // the first panic of a green thread, raised again once the scheduler stops
+    pub thread_panic: Option<Box<dyn std::any::Any + Send>>,
+    // the threads are driven one step at a time by a ManualScheduler on the OS
+    // thread's own context, which checkpoints and finished threads return to
+    pub manual: bool,
+    pub policy: crate::types::SchedulingPolicy,
+    pub timer: crate::timer::State,
+    pub groups: crate::group::Groups,
+    pub deterministic: crate::deterministic::State,
+    pub record: crate::record::State,
+    pub clock: crate::clock::State,
+    // work spawned through a Handle, taken at every switch
+    pub injector: Option<std::sync::Arc<crate::handle::Injector>>,
+    pub report: crate::types::RunReport,
+    // stacks of the live threads
+    pub stack_memory: usize,
+    pub main_thread: crate::types::ThreadId,
+    pub shutdown_policy: crate::types::ShutdownPolicy,
+    pub shutdown_timeout: Option<std::time::Duration>,
+    // set once the remaining threads have been cancelled; past the deadline
+    // they are dropped where they stand
+    pub shutting_down: bool,
+    pub shutdown_deadline: Option<std::time::Instant>,
+    pub max_threads: Option<usize>,
+    pub max_stack_memory: Option<usize>,
+    // threads that have exited, for spawn_wait to tell a freed slot from a
+    // deadlock
+    pub exits: u64,
+    pub stacks: crate::stack::Pool,
+    // a spawn or yield of the running thread inside a critical section, run
+    // when the section ends; dropped once the thread is switched out anyway
+    pub deferred_switch: Option<(Option<crate::types::ThreadId>, crate::record::Reason)>,
+}
+
+impl Runtime {
+    fn new() -> Self {
+        Runtime {
+            ctx_main: None,
+            contexts: std::collections::VecDeque::new(),
+            ids: std::collections::HashSet::new(),
+            current_thread_id: 0,
+            default_stack_size: 2 * 1024 * 1024,
+            switched_in_at: None,
+            thread_errors: Vec::new(),
+            thread_panic: None,
+            manual: false,
+            policy: crate::types::SchedulingPolicy::FairShare,
+            timer: crate::timer::State::new(),
+            groups: crate::group::Groups::new(),
+            deterministic: crate::deterministic::State::default(),
+            record: crate::record::State::default(),
+            clock: crate::clock::State::default(),
+            injector: None,
+            report: crate::types::RunReport::default(),
+            stack_memory: 0,
+            main_thread: 0,
+            shutdown_policy: crate::types::ShutdownPolicy::WaitAll,
+            shutdown_timeout: None,
+            shutting_down: false,
+            shutdown_deadline: None,
+            max_threads: None,
+            max_stack_memory: None,
+            exits: 0,
+            stacks: crate::stack::Pool::new(),
+            deferred_switch: None,
+        }
+    }
+}
 
 thread_local! {
-    static CURRENT_FUNCTION: std::cell::RefCell<Option<Box<dyn crate::types::Executable>>> = std::cell::RefCell::new(None);
+    // the runtime of the scheduler running on this OS thread, null outside
+    // one; const so the preemption signal handler can read it
+    static CURRENT: std::cell::Cell<*mut Runtime> = const { std::cell::Cell::new(std::ptr::null_mut()) };
+    // errors and panic of the last runtime that ran on this OS thread
+    static LEFT_ERRORS: std::cell::RefCell<Vec<crate::error::Error>> = const { std::cell::RefCell::new(Vec::new()) };
+    static LEFT_PANIC: std::cell::RefCell<Option<Box<dyn std::any::Any + Send>>> = const { std::cell::RefCell::new(None) };
+}
+
+pub fn current() -> *mut Runtime {
+    CURRENT.with(|c| c.get())
+}
+
+// the current runtime, for what only makes sense inside a scheduler
+pub fn runtime() -> *mut Runtime {
+    let runtime = current();
+    assert!(
+        !runtime.is_null(),
+        "no lachesis scheduler is running on this thread"
+    );
+    runtime
+}
+
+// a fresh runtime, current on this OS thread until dropped
+pub struct Entered {
+    runtime: *mut Runtime,
+    _overflow: crate::overflow::Installed,
+}
+
+pub fn enter() -> crate::error::Result<Entered> {
+    if !current().is_null() {
+        return Err(crate::error::Error::AlreadyInitialized);
+    }
+
+    let runtime = Box::into_raw(Box::new(Runtime::new()));
+    CURRENT.with(|c| c.set(runtime));
+    Ok(Entered {
+        runtime,
+        _overflow: crate::overflow::install(),
+    })
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        crate::timer::disable_preemption();
+        CURRENT.with(|c| c.set(std::ptr::null_mut()));
+        let mut runtime = unsafe { Box::from_raw(self.runtime) };
+        if let Some(injector) = runtime.injector.take() {
+            injector.detach();
+        }
+        LEFT_ERRORS.with(|l| *l.borrow_mut() = std::mem::take(&mut runtime.thread_errors));
+        LEFT_PANIC.with(|l| *l.borrow_mut() = runtime.thread_panic.take());
+    }
 }
 
 pub fn get_id() -> u64 {
+    crate::preempt::internal(next_id)
+}
+
+fn next_id() -> u64 {
     loop {
-        let rnd = rand::random::<u64>();
+        let rnd = crate::deterministic::next_id().unwrap_or_else(rand::random::<u64>);
         unsafe {
-            let id_ptr = &raw mut ID;
-            if !(**id_ptr).contains(&rnd) {
-                (**id_ptr).insert(rnd);
+            let ids_ptr = &raw mut (*runtime()).ids;
+            if (*ids_ptr).insert(rnd) {
                 return rnd;
             }
         }
     }
 }
 
-pub fn execute_main<F>(wrapper: F, stack_size: usize, preemption_interval: u64)
+// runs main as the first green thread of the current runtime until every
+// thread is done
+pub fn execute_main<F>(
+    main: F,
+    stack_size: usize,
+    preemption_interval: std::time::Duration,
+) -> crate::error::Result<()>
 where
     F: FnOnce() + Send + 'static,
 {
-    CURRENT_FUNCTION.with(|f| {
-        *f.borrow_mut() = Some(Box::new(wrapper));
-    });
+    unsafe {
+        let runtime = runtime();
+        let ctx_main_ptr = &raw mut (*runtime).ctx_main;
+        if (*ctx_main_ptr).is_some() {
+            panic!("spawn_from_main is called twice");
+        }
+
+        // set_context returns twice, so nothing that needs dropping may be
+        // moved after it
+        let mut first_ctx = new_context(stack_size)?;
+        *ctx_main_ptr = Some(Box::new(crate::context::Registers::new(0)));
+
+        // the main context is never preempted asynchronously
+        let outer_depth = crate::preempt::depth();
+        crate::preempt::set_depth(outer_depth + 1);
 
-    fn main_entry() {
-        CURRENT_FUNCTION.with(|f| {
-            if let Some(func) = f.borrow_mut().take() {
-                func.execute();
+        if let Some(ctx) = &mut *ctx_main_ptr {
+            (*runtime).default_stack_size = stack_size;
+            if let Err(e) = crate::enable_preemption_with_interval(preemption_interval) {
+                crate::timer::disable_preemption();
+                crate::preempt::set_depth(outer_depth);
+                *ctx_main_ptr = None;
+                crate::stack::give(first_ctx.stack);
+                (*runtime).ids.remove(&first_ctx.id);
+                return Err(e);
             }
-        });
-    }
 
-    spawn_from_main(main_entry, stack_size, preemption_interval);
+            first_ctx.executable = Some(Box::new(main));
+            first_ctx.name = Some("main".to_string());
+            crate::record::on_spawn(first_ctx.id);
+            on_spawn(&mut first_ctx);
+            (*runtime).main_thread = first_ctx.id;
+            first_ctx.state = crate::ThreadState::Running;
+            (*runtime).contexts.push_back(first_ctx);
+
+            if crate::context::set_context(&mut **ctx as *mut crate::context::Registers) == 0 {
+                run_front();
+            }
+
+            crate::timer::disable_preemption();
+            crate::preempt::set_depth(outer_depth);
+
+            (*runtime).ctx_main = None;
+            (*runtime).current_thread_id = 0;
+        }
+        Ok(())
+    }
 }
 
 pub fn spawn<F>(func: F, stack_size: usize) -> u64
 where
     F: FnOnce() + Send + 'static,
 {
+    crate::thread::ThreadBuilder::new()
+        .stack_size(stack_size)
+        .spawn(func)
+}
+
+// spawn, failing instead of panicking when the stack cannot be set up or no
+// scheduler is running
+pub fn try_spawn<F>(func: F, stack_size: usize) -> crate::error::Result<crate::types::ThreadId>
+where
+    F: FnOnce() + Send + 'static,
+{
+    crate::thread::ThreadBuilder::new()
+        .stack_size(stack_size)
+        .try_spawn(func)
+}
+
+// spawn, waiting while the scheduler is at its thread or stack memory limit
+pub fn spawn_wait<F>(func: F, stack_size: usize) -> crate::error::Result<crate::types::ThreadId>
+where
+    F: FnOnce() + Send + 'static,
+{
+    crate::thread::ThreadBuilder::new()
+        .stack_size(stack_size)
+        .spawn_wait(func)
+}
+
+// a context with a fresh id and stack, the id given back if the stack cannot
+// be had; CapacityExceeded if the limits leave no room for it
+pub fn new_context(stack_size: usize) -> crate::error::Result<Box<crate::context::Context>> {
     unsafe {
-        let id = get_id();
+        let runtime = runtime();
+        let threads = (*runtime).contexts.len();
+        let stack_memory = (*runtime).stack_memory;
+        let class = crate::stack::class(stack_size).unwrap_or(usize::MAX);
+        if (*runtime).max_threads.is_some_and(|max| threads >= max)
+            || (*runtime)
+                .max_stack_memory
+                .is_some_and(|max| stack_memory.saturating_add(class) > max)
+        {
+            return Err(crate::error::Error::CapacityExceeded {
+                threads,
+                stack_memory,
+            });
+        }
+    }
 
-        let mut ctx = Box::new(crate::context::Context::new(None, stack_size, id));
-        ctx.executable = Some(Box::new(func));
+    let id = get_id();
+    match crate::context::Context::new(None, stack_size, id) {
+        Ok(ctx) => Ok(Box::new(ctx)),
+        Err(e) => {
+            unsafe { (*runtime()).ids.remove(&id) };
+            Err(e)
+        }
+    }
+}
 
+pub fn spawn_context(mut ctx: Box<crate::context::Context>) -> u64 {
+    let id = crate::preempt::internal(|| unsafe {
+        let id = ctx.id;
         ctx.state = crate::ThreadState::Ready;
-        let contexts_ptr = &raw mut CONTEXTS;
-        (*contexts_ptr).push_back(ctx);
-        schedule();
+        crate::group::record_spawn(ctx.group);
+        crate::record::on_spawn(id);
+        on_spawn(&mut ctx);
+        (*runtime()).contexts.push_back(ctx);
         id
+    });
+    if !is_manual() {
+        schedule_for(crate::record::Reason::Spawn);
+    }
+    id
+}
+
+// a thread about to join contexts: count it in the run report, and cancel it
+// right away if the scheduler is shutting down
+pub fn on_spawn(ctx: &mut crate::context::Context) {
+    unsafe {
+        let runtime = runtime();
+        if (*runtime).shutting_down {
+            ctx.cancelled = Some(crate::error::Error::Cancelled(ctx.id));
+        }
+        let report = &raw mut (*runtime).report;
+        (*runtime).stack_memory += ctx.stack.size();
+        (*report).threads_spawned += 1;
+        (*report).peak_threads = (*report).peak_threads.max((*runtime).contexts.len() + 1);
+        (*report).peak_stack_memory = (*report).peak_stack_memory.max((*runtime).stack_memory);
     }
 }
 
+pub fn report() -> crate::types::RunReport {
+    unsafe { (*runtime()).report }
+}
+
+pub fn default_stack_size() -> usize {
+    unsafe { (*runtime()).default_stack_size }
+}
+
 pub fn schedule() {
+    schedule_for(crate::record::Reason::Yield)
+}
+
+pub fn schedule_for(reason: crate::record::Reason) {
+    unsafe { reschedule(None, true, reason) }
+}
+
+// put the running thread to sleep until deadline and run another one
+pub fn sleep_current(deadline: std::time::Instant) {
     unsafe {
-        let contexts_ptr = &raw mut CONTEXTS;
-        if (*contexts_ptr).len() <= 1 {
+        let contexts_ptr = &raw mut (*runtime()).contexts;
+        if let Some(current) = (*contexts_ptr).front_mut() {
+            current.state = crate::ThreadState::Sleeping;
+            current.wake_at = Some(deadline);
+        }
+        reschedule(None, true, crate::record::Reason::Sleep);
+    }
+}
+
+// called from the preemption signal handler while a green thread is interrupted
+// outside of any no-preempt section; the kernel saved its full register state,
+// including FP/SIMD registers, in the signal frame on the thread's own stack.
+// Code outside an async_preemptible section may hold std locks, which are owned
+// by the OS thread and would be taken again by the next green thread, so there
+// the tick is left to the thread's next checkpoint
+pub unsafe fn preempt_from_signal() {
+    unsafe {
+        let runtime = runtime();
+        let contexts_ptr = &raw const (*runtime).contexts;
+        let Some(current) = (*contexts_ptr).front() else {
+            return;
+        };
+        if current.id != (*runtime).current_thread_id || current.async_sections == 0 {
+            return;
+        }
+
+        // unwinding cannot cross the signal frame, a cancellation is
+        // picked up at the thread's next checkpoint instead
+        reschedule(None, false, crate::record::Reason::Signal);
+    }
+}
+
+// count the running green thread into or out of an async_preemptible section
+pub fn change_async_sections(enter: bool) {
+    let runtime = current();
+    if runtime.is_null() {
+        return;
+    }
+
+    // the signal handler reads the count
+    crate::preempt::disable();
+    unsafe {
+        let contexts_ptr = &raw mut (*runtime).contexts;
+        if let Some(ctx) = (*contexts_ptr).front_mut()
+            && ctx.id == (*runtime).current_thread_id
+        {
+            if enter {
+                ctx.async_sections += 1;
+            } else {
+                ctx.async_sections -= 1;
+            }
+        }
+    }
+    crate::preempt::enable();
+}
+
+// switch directly to a ready thread, bypassing the scheduling policy
+pub fn yield_to(id: crate::types::ThreadId) -> crate::error::Result<()> {
+    let runtime = current();
+    if runtime.is_null() {
+        return Err(crate::error::Error::NotInitialized);
+    }
+
+    let ready = crate::preempt::internal(|| unsafe {
+        let contexts_ptr = &raw const (*runtime).contexts;
+        let Some(target) = (*contexts_ptr).iter().find(|c| c.id == id) else {
+            return Err(crate::error::Error::ThreadNotFound(id));
+        };
+
+        match target.state {
+            crate::ThreadState::Running => Ok(false),
+            crate::ThreadState::Ready => Ok(true),
+            _ => Err(crate::error::Error::ThreadNotFound(id)),
+        }
+    })?;
+    if ready {
+        unsafe { reschedule(Some(id), true, crate::record::Reason::YieldTo) };
+    }
+    Ok(())
+}
+
+// run the switch deferred by a spawn or yield inside the critical section
+// that just ended; returns whether there was one
+pub fn run_deferred_switch() -> bool {
+    unsafe {
+        let runtime = current();
+        if runtime.is_null() {
+            return false;
+        }
+        let Some((target, reason)) = (*runtime).deferred_switch.take() else {
+            return false;
+        };
+        reschedule(target, true, reason);
+        true
+    }
+}
+
+unsafe fn reschedule(
+    target: Option<crate::types::ThreadId>,
+    unwind: bool,
+    reason: crate::record::Reason,
+) {
+    unsafe {
+        let runtime = current();
+        // the controller of a ManualScheduler is not one of the threads
+        if runtime.is_null() || ((*runtime).manual && (*runtime).current_thread_id == 0) {
+            return;
+        }
+
+        // a spawn or yield inside a critical section switches once the section
+        // ends; a sleep or a wait for capacity cannot go on without one
+        if crate::preempt::depth() > 0
+            && matches!(
+                reason,
+                crate::record::Reason::Spawn
+                    | crate::record::Reason::Yield
+                    | crate::record::Reason::YieldTo
+            )
+        {
+            (*runtime).deferred_switch = Some((target, reason));
+            return;
+        }
+
+        let contexts_ptr = &raw mut (*runtime).contexts;
+        let Some(current) = (*contexts_ptr).front_mut() else {
+            return;
+        };
+
+        crate::preempt::disable();
+        charge_current(current);
+        if unwind
+            && !std::thread::panicking()
+            && let Some(error) = current.cancelled.take()
+        {
+            crate::preempt::enable();
+            std::panic::resume_unwind(Box::new(crate::types::Cancelled(error)));
+        }
+
+        if is_manual() {
+            return_to_controller(unwind);
+            return;
+        }
+
+        crate::handle::drain();
+        if (*contexts_ptr).len() <= 1
+            && current.state == crate::ThreadState::Running
+            && !shutdown_overdue()
+        {
+            crate::preempt::enable();
             return;
         }
 
         let mut ctx = (*contexts_ptr).pop_front().unwrap();
-        ctx.state = crate::ThreadState::Ready;
+        if ctx.state == crate::ThreadState::Running {
+            ctx.state = crate::ThreadState::Ready;
+        }
+        ctx.preempt_depth = crate::preempt::depth();
+        let from = ctx.id;
         let regs = ctx.get_regs_mut();
         (*contexts_ptr).push_back(ctx);
 
-        if crate::context::set_context(regs) == 0
-            && let Some(next) = (*contexts_ptr).front_mut()
+        if crate::context::set_context(regs) == 0 {
+            match target.and_then(|id| (*contexts_ptr).iter().position(|c| c.id == id)) {
+                Some(index) => {
+                    let ctx = (*contexts_ptr).remove(index).unwrap();
+                    (*contexts_ptr).push_front(ctx);
+                }
+                None => pick_next(),
+            }
+            if let Some(next) = (*contexts_ptr).front() {
+                crate::record::on_switch(from, reason, next.id);
+            }
+            run_front();
+        }
+
+        // resumed; unwind if the thread was cancelled while it was suspended,
+        // but never from a destructor that runs for a panic already, the
+        // cancellation then waits for a later switch
+        crate::preempt::enable();
+        let current = (*contexts_ptr).front_mut().unwrap();
+        if unwind
+            && !std::thread::panicking()
+            && let Some(error) = current.cancelled.take()
         {
-            next.state = crate::ThreadState::Running;
-            let current_id_ptr = &raw mut CURRENT_THREAD_ID;
-            *current_id_ptr = next.id;
-            crate::context::switch_context(next.get_regs());
+            std::panic::resume_unwind(Box::new(crate::types::Cancelled(error)));
+        }
+    }
+}
+
+// account the CPU time used by the running thread since it was switched in
+unsafe fn charge_current(ctx: &mut crate::context::Context) {
+    unsafe {
+        let now = crate::clock::now();
+        let switched_ptr = &raw mut (*runtime()).switched_in_at;
+        let elapsed = now.saturating_duration_since((*switched_ptr).unwrap_or(now));
+        *switched_ptr = Some(now);
+        ctx.cpu_time += elapsed;
+        crate::group::charge(ctx.group, elapsed);
+
+        if let Some(budget) = ctx.budget.as_mut() {
+            match budget.charge(elapsed, now) {
+                crate::budget::Verdict::Within => {}
+                crate::budget::Verdict::Throttle(until) => {
+                    ctx.state = crate::ThreadState::Throttled;
+                    ctx.wake_at = Some(until);
+                }
+                crate::budget::Verdict::Terminate => {
+                    ctx.cancelled = Some(crate::error::Error::BudgetExceeded {
+                        id: ctx.id,
+                        used: ctx.cpu_time,
+                    });
+                }
+            }
         }
     }
 }
 
+// move the next thread to run to the front of contexts, waiting for the
+// clock while every thread is throttled or asleep
+unsafe fn pick_next() {
+    unsafe {
+        let contexts_ptr = &raw mut (*runtime()).contexts;
+        loop {
+            crate::handle::drain();
+            if shutdown_overdue() {
+                force_shutdown();
+            }
+            let now = crate::clock::now();
+            let mut earliest: Option<std::time::Instant> = None;
+            let mut runnable = Vec::new();
+            let mut ready = Vec::new();
+            let mut waiting = false;
+
+            for (index, ctx) in (*contexts_ptr).iter_mut().enumerate() {
+                if ctx.state == crate::ThreadState::WaitingForCapacity {
+                    waiting = true;
+                    continue;
+                }
+                if matches!(
+                    ctx.state,
+                    crate::ThreadState::Throttled | crate::ThreadState::Sleeping
+                ) && let Some(until) = ctx.wake_at
+                {
+                    if until > now {
+                        earliest = Some(earliest.map_or(until, |e| e.min(until)));
+                        continue;
+                    }
+
+                    if ctx.state == crate::ThreadState::Throttled
+                        && let Some(budget) = ctx.budget.as_mut()
+                    {
+                        budget.refill(now);
+                    }
+                    ctx.wake_at = None;
+                    ctx.state = crate::ThreadState::Ready;
+                }
+                runnable.push((index, ctx.group));
+                ready.push(ctx.id);
+            }
+
+            let policy_ptr = &raw const (*runtime()).policy;
+            let chosen = if crate::deterministic::is_enabled() {
+                crate::deterministic::pick(&ready).map(|i| runnable[i].0)
+            } else {
+                match *policy_ptr {
+                    crate::types::SchedulingPolicy::RoundRobin => runnable.first().map(|&(i, _)| i),
+                    crate::types::SchedulingPolicy::FairShare => crate::group::pick(&runnable),
+                }
+            };
+
+            if let Some(index) = chosen {
+                let ctx = (*contexts_ptr).remove(index).unwrap();
+                (*contexts_ptr).push_front(ctx);
+                return;
+            }
+
+            // every thread waits for another to exit; wake them to fail
+            if waiting && earliest.is_none() {
+                for ctx in (*contexts_ptr).iter_mut() {
+                    if ctx.state == crate::ThreadState::WaitingForCapacity {
+                        ctx.state = crate::ThreadState::Ready;
+                    }
+                }
+                continue;
+            }
+
+            // stragglers of a shutdown are not waited for past its deadline
+            let deadline_ptr = &raw const (*runtime()).shutdown_deadline;
+            match earliest.map(|e| (*deadline_ptr).map_or(e, |d| e.min(d))) {
+                Some(until) => crate::clock::wait_until(until),
+                None => return,
+            }
+        }
+    }
+}
+
+pub fn set_shutdown(policy: crate::types::ShutdownPolicy, timeout: Option<std::time::Duration>) {
+    unsafe {
+        (*runtime()).shutdown_policy = policy;
+        (*runtime()).shutdown_timeout = timeout;
+    }
+}
+
+// cancel every remaining thread, waking those asleep so they unwind promptly
+unsafe fn begin_shutdown() {
+    unsafe {
+        let runtime = runtime();
+        (*runtime).shutting_down = true;
+        (*runtime).shutdown_deadline = (*runtime)
+            .shutdown_timeout
+            .map(|timeout| crate::clock::now() + timeout);
+
+        let contexts_ptr = &raw mut (*runtime).contexts;
+        for ctx in (*contexts_ptr).iter_mut() {
+            if ctx.cancelled.is_none() {
+                ctx.cancelled = Some(crate::error::Error::Cancelled(ctx.id));
+            }
+            if matches!(
+                ctx.state,
+                crate::ThreadState::Throttled
+                    | crate::ThreadState::Sleeping
+                    | crate::ThreadState::WaitingForCapacity
+            ) {
+                ctx.state = crate::ThreadState::Ready;
+                ctx.wake_at = None;
+            }
+        }
+    }
+}
+
+fn shutdown_overdue() -> bool {
+    unsafe {
+        (*runtime())
+            .shutdown_deadline
+            .is_some_and(|deadline| crate::clock::now() >= deadline)
+    }
+}
+
+// the graceful shutdown took too long: drop the remaining threads without
+// unwinding them and return to the caller of run; called with preemption
+// disabled from one of those threads, whose stack is retired like that of an
+// exiting thread
+unsafe fn force_shutdown() -> ! {
+    unsafe {
+        let runtime = runtime();
+        let contexts_ptr = &raw mut (*runtime).contexts;
+        for ctx in (*contexts_ptr).drain(..) {
+            (*runtime).ids.remove(&ctx.id);
+            (*runtime).stack_memory -= ctx.stack.size();
+            if ctx.id == (*runtime).current_thread_id {
+                crate::stack::retire(ctx.stack);
+            } else {
+                crate::stack::give(ctx.stack);
+            }
+            (*runtime)
+                .thread_errors
+                .push(crate::error::Error::Cancelled(ctx.id));
+        }
+
+        crate::timer::disable_preemption();
+        let ctx_main_ptr = &raw const (*runtime).ctx_main;
+        if let Some(c) = &*ctx_main_ptr {
+            crate::context::switch_context(&**c as *const crate::context::Registers);
+        }
+        unreachable!();
+    }
+}
+
+pub fn set_limits(max_threads: Option<usize>, max_stack_memory: Option<usize>) {
+    unsafe {
+        (*runtime()).max_threads = max_threads;
+        (*runtime()).max_stack_memory = max_stack_memory;
+    }
+}
+
+// park the running thread until another one exits; Deadlock if every thread
+// is waiting and none can
+pub fn wait_for_capacity() -> crate::error::Result<()> {
+    unsafe {
+        let runtime = runtime();
+        let exits = (*runtime).exits;
+        let contexts_ptr = &raw mut (*runtime).contexts;
+        if let Some(current) = (*contexts_ptr).front_mut() {
+            current.state = crate::ThreadState::WaitingForCapacity;
+        }
+        reschedule(None, true, crate::record::Reason::Capacity);
+
+        if (*runtime).exits == exits {
+            return Err(crate::error::Error::Deadlock);
+        }
+        Ok(())
+    }
+}
+
+// wake the threads in spawn_wait
+unsafe fn release_capacity() {
+    unsafe {
+        let runtime = runtime();
+        (*runtime).exits += 1;
+        let contexts_ptr = &raw mut (*runtime).contexts;
+        for ctx in (*contexts_ptr).iter_mut() {
+            if ctx.state == crate::ThreadState::WaitingForCapacity {
+                ctx.state = crate::ThreadState::Ready;
+            }
+        }
+    }
+}
+
+pub fn set_injector(injector: std::sync::Arc<crate::handle::Injector>) {
+    injector.attach();
+    unsafe { (*runtime()).injector = Some(injector) };
+}
+
+pub fn set_policy(policy: crate::types::SchedulingPolicy) {
+    unsafe { (*runtime()).policy = policy };
+}
+
+pub fn current_group() -> crate::group::GroupId {
+    unsafe {
+        current()
+            .as_ref()
+            .and_then(|r| r.contexts.front())
+            .map_or(crate::group::ROOT_GROUP, |c| c.group)
+    }
+}
+
+pub fn count_threads(filter: impl Fn(crate::group::GroupId) -> bool) -> usize {
+    unsafe {
+        let contexts_ptr = &raw const (*runtime()).contexts;
+        (*contexts_ptr).iter().filter(|c| filter(c.group)).count()
+    }
+}
+
+pub fn cancel_threads(filter: impl Fn(crate::group::GroupId) -> bool) {
+    let cancelled_itself = crate::preempt::internal(|| unsafe {
+        let contexts_ptr = &raw mut (*runtime()).contexts;
+        for ctx in (*contexts_ptr).iter_mut() {
+            if filter(ctx.group) && ctx.cancelled.is_none() {
+                ctx.cancelled = Some(crate::error::Error::Cancelled(ctx.id));
+            }
+        }
+        (*contexts_ptr)
+            .front()
+            .is_some_and(|c| c.cancelled.is_some())
+    });
+
+    // the caller may have cancelled itself
+    if cancelled_itself {
+        schedule();
+    }
+}
+
+// switch to the thread at the front of contexts
+unsafe fn run_front() -> ! {
+    unsafe {
+        let runtime = runtime();
+        let contexts_ptr = &raw mut (*runtime).contexts;
+        let ready = (*contexts_ptr)
+            .iter()
+            .filter(|c| {
+                !matches!(
+                    c.state,
+                    crate::ThreadState::Throttled
+                        | crate::ThreadState::Sleeping
+                        | crate::ThreadState::WaitingForCapacity
+                )
+            })
+            .count();
+        let next = (*contexts_ptr).front_mut().unwrap();
+        next.state = crate::ThreadState::Running;
+        if (*runtime).current_thread_id != next.id {
+            (*runtime).report.switches += 1;
+        }
+        (*runtime).current_thread_id = next.id;
+        crate::timer::start_slice(next.quantum, ready);
+        (*runtime).switched_in_at = Some(crate::clock::now());
+        (*runtime).deferred_switch = None;
+        crate::preempt::set_depth(next.preempt_depth);
+        crate::context::switch_context(next.get_regs());
+    }
+}
+
+// 0 outside a green thread
+pub fn current_thread_id() -> crate::types::ThreadId {
+    unsafe { current().as_ref().map_or(0, |r| r.current_thread_id) }
+}
+
+// the panic of the running scheduler, or of the last one on this OS thread
+pub fn take_thread_panic() -> Option<Box<dyn std::any::Any + Send>> {
+    unsafe {
+        match current().as_mut() {
+            Some(runtime) => runtime.thread_panic.take(),
+            None => LEFT_PANIC.with(|l| l.borrow_mut().take()),
+        }
+    }
+}
+
+// errors of green threads the runtime terminated, such as exhausted CPU
+// budgets; after a run, those of the last scheduler on this OS thread
+pub fn take_thread_errors() -> Vec<crate::error::Error> {
+    crate::preempt::internal(|| unsafe {
+        match current().as_mut() {
+            Some(runtime) => std::mem::take(&mut runtime.thread_errors),
+            None => LEFT_ERRORS.with(|l| std::mem::take(&mut *l.borrow_mut())),
+        }
+    })
+}
+
 // entry point for green threads
 #[unsafe(no_mangle)]
 pub extern "C" fn entry_point() -> ! {
     unsafe {
-        let contexts_ptr = &raw mut CONTEXTS;
+        // released from the thread that switched here
+        crate::preempt::enable();
+
+        let runtime = runtime();
+        let contexts_ptr = &raw mut (*runtime).contexts;
         let ctx = (*contexts_ptr).front_mut().unwrap();
 
-        if let Some(executable) = ctx.executable.take() {
-            executable.execute();
-        } else if let Some(entry) = ctx.entry {
-            entry();
+        let executable = ctx.executable.take();
+        let entry = ctx.entry;
+        let cancelled = ctx.cancelled.take();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+            // cancelled before it ever ran
+            if let Some(error) = cancelled {
+                std::panic::resume_unwind(Box::new(crate::types::Cancelled(error)));
+            }
+
+            if let Some(executable) = executable {
+                executable.execute();
+            } else if let Some(entry) = entry {
+                entry();
+            }
+        }));
+
+        if let Err(payload) = result {
+            match payload.downcast::<crate::types::Cancelled>() {
+                Ok(cancelled) => {
+                    (*runtime).thread_errors.push(cancelled.0);
+                }
+                // there is nothing to unwind into above the entry point, the
+                // caller of run gets it instead and the other threads are cancelled
+                Err(payload) => {
+                    let panic_ptr = &raw mut (*runtime).thread_panic;
+                    if (*panic_ptr).is_none() {
+                        *panic_ptr = Some(payload);
+                    }
+                    for ctx in (*contexts_ptr).iter_mut().skip(1) {
+                        if ctx.cancelled.is_none() {
+                            ctx.cancelled = Some(crate::error::Error::Cancelled(ctx.id));
+                        }
+                    }
+                }
+            }
         }
 
         // Thread cleanup
-        let contexts_ptr = &raw mut CONTEXTS;
+        crate::preempt::disable();
         let mut ctx = (*contexts_ptr).pop_front().unwrap();
         ctx.state = crate::ThreadState::Terminated;
 
         // Remove thread ID
-        let id_ptr = &raw const ID;
-        if !(*id_ptr).is_null() {
-            let id_ptr = &raw mut ID;
-            (**id_ptr).remove(&ctx.id);
-        }
+        (*runtime).ids.remove(&ctx.id);
+        (*runtime).stack_memory -= ctx.stack.size();
+        release_capacity();
+        crate::stack::retire(ctx.stack);
 
-        let unused_ptr = &raw mut UNUSED_STACK;
-        *unused_ptr = (ctx.stack, ctx.stack_layout);
+        let ctx_main_ptr = &raw const (*runtime).ctx_main;
+        if !(*runtime).manual {
+            crate::handle::drain();
 
-        match (*contexts_ptr).front_mut() {
-            Some(c) => {
-                c.state = crate::ThreadState::Running;
-                let current_id_ptr = &raw mut CURRENT_THREAD_ID;
-                *current_id_ptr = c.id;
-                crate::context::switch_context(c.get_regs());
+            // only daemons left, or the main thread returned under MainExits
+            let main_exited = ctx.id == (*runtime).main_thread
+                && (*runtime).shutdown_policy == crate::types::ShutdownPolicy::MainExits;
+            if !(*runtime).shutting_down
+                && (main_exited || (*contexts_ptr).iter().all(|c| c.daemon))
+            {
+                begin_shutdown();
             }
-            None => {
-                // All threads finished - return to main
-                crate::timer::disable_preemption();
-                let ctx_main_ptr = &raw const CTX_MAIN;
-                if let Some(c) = &*ctx_main_ptr {
-                    crate::context::switch_context(&**c as *const crate::context::Registers);
-                }
+        }
+        if (*runtime).manual {
+            if let Some(c) = &*ctx_main_ptr {
+                crate::context::switch_context(&**c as *const crate::context::Registers);
             }
-        };
+        } else if (*contexts_ptr).is_empty() {
+            // All threads finished - return to main
+            crate::timer::disable_preemption();
+            if let Some(c) = &*ctx_main_ptr {
+                crate::context::switch_context(&**c as *const crate::context::Registers);
+            }
+        } else {
+            pick_next();
+            if let Some(next) = (*contexts_ptr).front() {
+                crate::record::on_switch(ctx.id, crate::record::Reason::Exit, next.id);
+            }
+            run_front();
+        }
     }
 
     unreachable!();
 }
 
-pub fn spawn_from_main(func: crate::types::Entry, stack_size: usize, preemption_interval: u64) {
+pub fn is_shutting_down() -> bool {
+    unsafe { (*runtime()).shutting_down }
+}
+
+pub fn is_manual() -> bool {
+    unsafe { current().as_ref().is_some_and(|r| r.manual) }
+}
+
+// a checkpoint of a manually driven thread: suspend it and give control back
+// to the test; called with preemption disabled
+unsafe fn return_to_controller(unwind: bool) {
     unsafe {
-        let ctx_main_ptr = &raw const CTX_MAIN;
-        if (*ctx_main_ptr).is_some() {
-            panic!("spawn_from_main is called twice");
+        let runtime = runtime();
+        let contexts_ptr = &raw mut (*runtime).contexts;
+        let ctx = (*contexts_ptr).front_mut().unwrap();
+        if ctx.state == crate::ThreadState::Running {
+            ctx.state = crate::ThreadState::Ready;
         }
+        ctx.preempt_depth = crate::preempt::depth();
+        let regs = ctx.get_regs_mut();
 
-        let ctx_main_ptr = &raw mut CTX_MAIN;
-        *ctx_main_ptr = Some(Box::new(crate::context::Registers::new(0)));
+        if crate::context::set_context(regs) == 0 {
+            let ctx_main_ptr = &raw const (*runtime).ctx_main;
+            if let Some(c) = &*ctx_main_ptr {
+                crate::context::switch_context(&**c as *const crate::context::Registers);
+            }
+        }
 
-        if let Some(ctx) = &mut *ctx_main_ptr {
-            let mut ids = std::collections::HashSet::new();
-            let id_ptr = &raw mut ID;
-            *id_ptr = &mut ids as *mut std::collections::HashSet<u64>;
+        // resumed by the controller
+        crate::preempt::enable();
+        let current = (*contexts_ptr).front_mut().unwrap();
+        if unwind && let Some(error) = current.cancelled.take() {
+            std::panic::resume_unwind(Box::new(crate::types::Cancelled(error)));
+        }
+    }
+}
 
-            crate::enable_preemption_with_interval(preemption_interval);
+// sets the current runtime up for a ManualScheduler; returns the
+// controller's preemption depth to restore in manual_end
+pub unsafe fn manual_begin(stack_size: usize) -> usize {
+    unsafe {
+        let runtime = runtime();
+        (*runtime).ctx_main = Some(Box::new(crate::context::Registers::new(0)));
+        (*runtime).default_stack_size = stack_size;
+        (*runtime).manual = true;
 
-            if crate::context::set_context(&mut **ctx as *mut crate::context::Registers) == 0 {
-                let mut first_ctx = Box::new(crate::context::Context::new(
-                    Some(func),
-                    stack_size,
-                    get_id(),
-                ));
-                first_ctx.state = crate::ThreadState::Running;
-                let current_id_ptr = &raw mut CURRENT_THREAD_ID;
-                *current_id_ptr = first_ctx.id;
-                let contexts_ptr = &raw mut CONTEXTS;
-                (*contexts_ptr).push_back(first_ctx);
+        // checkpoints on the controller itself are not preemption points
+        let outer_depth = crate::preempt::depth();
+        crate::preempt::set_depth(outer_depth + 1);
+        outer_depth
+    }
+}
 
-                let first = (*contexts_ptr).front().unwrap();
-                crate::context::switch_context(first.get_regs());
-            }
+pub unsafe fn manual_end(outer_depth: usize) {
+    unsafe {
+        let runtime = runtime();
+        (*runtime).manual = false;
+        (*runtime).ctx_main = None;
+        crate::preempt::set_depth(outer_depth);
+    }
+}
 
-            crate::timer::disable_preemption();
+// run a thread from the controller until it reaches a checkpoint or finishes;
+// returns whether it finished
+pub unsafe fn manual_resume(id: crate::types::ThreadId) -> crate::error::Result<bool> {
+    unsafe {
+        let runtime = runtime();
+        let contexts_ptr = &raw mut (*runtime).contexts;
+        let Some(index) = (*contexts_ptr).iter().position(|c| c.id == id) else {
+            return Err(crate::error::Error::ThreadNotFound(id));
+        };
+        let ctx = (*contexts_ptr).remove(index).unwrap();
+        (*contexts_ptr).push_front(ctx);
 
-            let ctx_main_ptr = &raw mut CTX_MAIN;
-            *ctx_main_ptr = None;
-            let id_ptr = &raw mut ID;
-            *id_ptr = std::ptr::null_mut();
+        let depth = crate::preempt::depth();
+        let ctx_main_ptr = &raw mut (*runtime).ctx_main;
+        if let Some(ctx) = &mut *ctx_main_ptr
+            && crate::context::set_context(&mut **ctx as *mut crate::context::Registers) == 0
+        {
+            run_front();
+        }
+        crate::preempt::set_depth(depth);
+        (*runtime).current_thread_id = 0;
+
+        if let Some(payload) = take_thread_panic() {
+            std::panic::resume_unwind(payload);
+        }
+        Ok(!(*contexts_ptr).iter().any(|c| c.id == id))
+    }
+}
+
+pub fn thread_ids() -> Vec<crate::types::ThreadId> {
+    unsafe {
+        let contexts_ptr = &raw const (*runtime()).contexts;
+        (*contexts_ptr).iter().map(|c| c.id).collect()
+    }
+}
 
-            // Clear local collections
-            ids.clear();
+pub fn thread_state(id: crate::types::ThreadId) -> Option<crate::ThreadState> {
+    unsafe {
+        let contexts_ptr = &raw const (*runtime()).contexts;
+        (*contexts_ptr).iter().find(|c| c.id == id).map(|c| c.state)
+    }
+}
 
-            let unused_ptr = &raw mut UNUSED_STACK;
-            *unused_ptr = (std::ptr::null_mut(), std::alloc::Layout::new::<u8>());
+pub fn cancel_thread(id: crate::types::ThreadId) {
+    unsafe {
+        let contexts_ptr = &raw mut (*runtime()).contexts;
+        if let Some(ctx) = (*contexts_ptr).iter_mut().find(|c| c.id == id)
+            && ctx.cancelled.is_none()
+        {
+            ctx.cancelled = Some(crate::error::Error::Cancelled(id));
         }
     }
 }
+
+// runs func as the first green thread of the current runtime, or of a fresh
+// one when no scheduler runs on this OS thread, until every thread is done
+pub fn spawn_from_main(
+    func: crate::types::Entry,
+    stack_size: usize,
+    preemption_interval: std::time::Duration,
+) {
+    let _entered = current().is_null().then(|| enter().unwrap());
+    if let Err(e) = execute_main(func, stack_size, preemption_interval) {
+        panic!("cannot start the main green thread: {e}");
+    }
+}
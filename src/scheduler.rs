@@ -1,6 +1,7 @@
 pub struct Lachesis {
     config: crate::types::SchedulerConfig,
     initialized: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    injector: std::sync::Arc<crate::handle::Injector>,
 }
 
 impl Lachesis {
@@ -8,6 +9,7 @@ impl Lachesis {
         Lachesis {
             config,
             initialized: std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            injector: std::sync::Arc::new(crate::handle::Injector::new()),
         }
     }
 
@@ -15,33 +17,273 @@ impl Lachesis {
         ConfigBuilder::new()
     }
 
-    pub fn run<F>(&self, main_func: F) -> crate::error::Result<()>
+    // spawns into this scheduler from any OS thread, while it runs or ahead
+    // of its next run
+    pub fn handle(&self) -> crate::handle::Handle {
+        crate::handle::Handle::new(std::sync::Arc::clone(&self.injector))
+    }
+
+    // runs main_func as the first green thread until every thread is done and
+    // hands back its result; a panic of a green thread is raised again here
+    pub fn run<T, F>(&self, main_func: F) -> crate::error::Result<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run_with_report(main_func).map(|(value, _)| value)
+    }
+
+    // run, and report what the run did
+    pub fn run_with_report<T, F>(
+        &self,
+        main_func: F,
+    ) -> crate::error::Result<(T, crate::types::RunReport)>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let outcome = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let slot = std::sync::Arc::clone(&outcome);
+
+        let (_, report) = self.run_with(
+            move || {
+                // a cancelled main thread ends the run with its error, other
+                // panics are left to the scheduler
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(main_func));
+                *slot.lock().unwrap() = Some(match result {
+                    Ok(value) => Ok(value),
+                    Err(payload) => match payload.downcast::<crate::types::Cancelled>() {
+                        Ok(cancelled) => Err(cancelled.0),
+                        Err(payload) => std::panic::resume_unwind(payload),
+                    },
+                });
+            },
+            None,
+        )?;
+
+        let panic = crate::runtime::take_thread_panic();
+        let result = outcome.lock().unwrap().take();
+        // a failing deterministic run tells how to replay it
+        if let Some(seed) = report.seed
+            && (panic.is_some() || !matches!(result, Some(Ok(_))))
+        {
+            eprintln!(
+                "lachesis: deterministic run with seed {seed} failed, replay with {}={seed}",
+                crate::deterministic::SEED_VAR
+            );
+        }
+
+        if let Some(payload) = panic {
+            std::panic::resume_unwind(payload);
+        }
+        main_result(result).map(|value| (value, report))
+    }
+
+    // run under the given strategy, or the seeded one in deterministic mode,
+    // and return the scheduling decisions that were made; a panic of a green
+    // thread is left for the caller to take
+    pub(crate) fn run_with<F>(
+        &self,
+        main_func: F,
+        strategy: Option<Box<dyn crate::deterministic::Strategy>>,
+    ) -> crate::error::Result<(Vec<crate::deterministic::Decision>, crate::types::RunReport)>
     where
         F: FnOnce() + Send + 'static,
     {
+        let stack_size = self.config.default_stack_size;
+        if stack_size < crate::context::MIN_STACK_SIZE {
+            return std::result::Result::Err(crate::error::Error::InvalidStackSize {
+                size: stack_size,
+                min: crate::context::MIN_STACK_SIZE,
+            });
+        }
+
+        if let Some(adaptive) = self.config.adaptive_quantum
+            && (adaptive.min < crate::timer::MIN_QUANTUM || adaptive.min > adaptive.max)
+        {
+            return std::result::Result::Err(crate::error::Error::Configuration(format!(
+                "invalid adaptive quantum: {:?}..{:?}",
+                adaptive.min, adaptive.max
+            )));
+        }
+
+        crate::timer::check_quantum("preemption interval", self.config.preemption_interval)?;
+
+        if self.config.max_threads == Some(0) {
+            return std::result::Result::Err(crate::error::Error::Configuration(
+                "max threads must leave room for the main thread".to_string(),
+            ));
+        }
+
+        // the main thread's stack is charged at the size of its class
+        let main_stack = crate::stack::class(stack_size).unwrap_or(usize::MAX);
+        if self
+            .config
+            .max_stack_memory
+            .is_some_and(|max| max < main_stack)
+        {
+            return std::result::Result::Err(crate::error::Error::Configuration(format!(
+                "max stack memory must leave room for the main thread's {main_stack} byte stack"
+            )));
+        }
+
+        if self.config.stack_guard_size == 0 {
+            return std::result::Result::Err(crate::error::Error::Configuration(
+                "stack guard size must not be zero".to_string(),
+            ));
+        }
+
+        if self.config.checkpoint_budget == Some(0) {
+            return std::result::Result::Err(crate::error::Error::Configuration(
+                "checkpoint budget must not be zero".to_string(),
+            ));
+        }
+
+        if self.config.async_preemptible_sections
+            && (self.config.deterministic || strategy.is_some())
+        {
+            return std::result::Result::Err(crate::error::Error::Configuration(
+                "async preemptible sections cannot be used in deterministic mode".to_string(),
+            ));
+        }
+
+        if self.config.async_preemptible_sections
+            && (self.config.record.is_some() || self.config.replay.is_some())
+        {
+            return std::result::Result::Err(crate::error::Error::Configuration(
+                "switches from the signal handler cannot be replayed".to_string(),
+            ));
+        }
+
+        if self.config.replay.is_some() && (self.config.deterministic || strategy.is_some()) {
+            return std::result::Result::Err(crate::error::Error::Configuration(
+                "a replay cannot be combined with deterministic scheduling".to_string(),
+            ));
+        }
+
+        let replay = match &self.config.replay {
+            Some(path) => Some(crate::record::load(path)?),
+            None => None,
+        };
+
+        let (strategy, seed) = match strategy {
+            Some(strategy) => (Some(strategy), None),
+            None if replay.is_some() => {
+                let replay: Box<dyn crate::deterministic::Strategy> =
+                    Box::new(crate::record::Replay);
+                (Some(replay), None)
+            }
+            None if self.config.deterministic => {
+                let seed = crate::deterministic::resolve_seed(self.config.seed)?;
+                let random: Box<dyn crate::deterministic::Strategy> =
+                    Box::new(crate::deterministic::Random::new(seed));
+                (Some(random), Some(seed))
+            }
+            None => (None, None),
+        };
+
+        if self.config.async_preemptible_sections && self.config.virtual_clock {
+            return std::result::Result::Err(crate::error::Error::Configuration(
+                "async preemptible sections cannot be used with the virtual clock".to_string(),
+            ));
+        }
+
+        if self.config.async_preemptible_sections && !self.config.timer_preemption {
+            return std::result::Result::Err(crate::error::Error::Configuration(
+                "async preemptible sections require timer preemption".to_string(),
+            ));
+        }
+
+        if self.config.async_preemptible_sections && !crate::preempt::allocator_guarded() {
+            return std::result::Result::Err(crate::error::Error::Configuration(
+                "async preemptible sections require lachesis::PreemptSafeAlloc as the global allocator"
+                    .to_string(),
+            ));
+        }
+
+        if matches!(
+            self.config.preemption_signal,
+            nix::sys::signal::Signal::SIGKILL
+                | nix::sys::signal::Signal::SIGSTOP
+                | nix::sys::signal::Signal::SIGSEGV
+                | nix::sys::signal::Signal::SIGBUS
+        ) {
+            return std::result::Result::Err(crate::error::Error::Configuration(format!(
+                "{} cannot be used as the preemption signal",
+                self.config.preemption_signal
+            )));
+        }
+
+        // the configuration is checked by now; from here on every error
+        // gives the flag back
         if self
             .initialized
             .swap(true, std::sync::atomic::Ordering::SeqCst)
         {
             return std::result::Result::Err(crate::error::Error::AlreadyInitialized);
         }
+        let release = |e| {
+            self.initialized
+                .store(false, std::sync::atomic::Ordering::SeqCst);
+            Err(e)
+        };
 
-        let stack_size = self.config.default_stack_size;
-        if stack_size < 64 * 1024 {
-            return std::result::Result::Err(crate::error::Error::InvalidStackSize {
-                size: stack_size,
-                min: 64 * 1024, // Minimum 64KB
-            });
+        // truncated only once this is the one run of the scheduler
+        let recording = match self.config.record.as_deref().map(crate::record::create) {
+            Some(Ok(recording)) => Some(recording),
+            Some(Err(e)) => return release(e),
+            None => None,
+        };
+
+        // a scheduler of its own for this run; one already running on this OS
+        // thread is AlreadyInitialized
+        let _entered = match crate::runtime::enter() {
+            Ok(entered) => entered,
+            Err(e) => return release(e),
+        };
+
+        let preemption_interval = self.config.preemption_interval;
+        crate::timer::set_backend(self.config.timer_backend);
+        crate::timer::set_signal(self.config.preemption_signal);
+        crate::timer::set_adaptive_quantum(self.config.adaptive_quantum);
+        crate::runtime::set_policy(self.config.policy);
+        crate::runtime::set_injector(std::sync::Arc::clone(&self.injector));
+        crate::runtime::set_shutdown(self.config.shutdown_policy, self.config.shutdown_timeout);
+        crate::runtime::set_limits(self.config.max_threads, self.config.max_stack_memory);
+        crate::stack::set_pool_limit(self.config.stack_pool_limit);
+        crate::stack::set_guard_size(self.config.stack_guard_size);
+        crate::timer::set_async_sections(self.config.async_preemptible_sections);
+        crate::timer::set_checkpoint_budget(self.config.checkpoint_budget);
+        // the wall-clock timer would make runs differ
+        crate::timer::set_timer_preemption(self.config.timer_preemption && strategy.is_none());
+        if let Some(strategy) = strategy {
+            crate::deterministic::install(strategy, seed);
         }
+        crate::record::start(recording, replay);
+        crate::clock::set_virtual(self.config.virtual_clock);
+        crate::timer::set_fast_checkpoints(
+            !crate::deterministic::is_enabled()
+                && !crate::record::is_active()
+                && !self.config.virtual_clock,
+        );
 
-        let preemption_interval = self.config.preemption_interval_ms;
+        let started = std::time::Instant::now();
+        let cpu_started = thread_cpu_time();
+        let started_main = crate::runtime::execute_main(main_func, stack_size, preemption_interval);
+        let mut report = crate::runtime::report();
+        report.wall_time = started.elapsed();
+        report.cpu_time = thread_cpu_time().saturating_sub(cpu_started);
+        report.seed = crate::deterministic::seed();
 
-        crate::runtime::execute_main(main_func, stack_size, preemption_interval);
+        let decisions = crate::deterministic::uninstall();
+        crate::clock::set_virtual(false);
 
         self.initialized
             .store(false, std::sync::atomic::Ordering::SeqCst);
 
-        Ok(())
+        crate::record::finish()?;
+        started_main?;
+        Ok((decisions, report))
     }
 }
 
@@ -67,8 +309,126 @@ impl ConfigBuilder {
         self
     }
 
-    pub fn preemption_interval(mut self, ms: u64) -> Self {
-        self.config.preemption_interval_ms = ms;
+    pub fn preemption_interval(mut self, interval: std::time::Duration) -> Self {
+        self.config.preemption_interval = interval;
+        self
+    }
+
+    pub fn timer_backend(mut self, backend: crate::types::TimerBackend) -> Self {
+        self.config.timer_backend = backend;
+        self
+    }
+
+    // defaults to SIGALRM; a handler installed before is chained and restored
+    pub fn preemption_signal(mut self, signal: nix::sys::signal::Signal) -> Self {
+        self.config.preemption_signal = signal;
+        self
+    }
+
+    pub fn adaptive_quantum(mut self, min: std::time::Duration, max: std::time::Duration) -> Self {
+        self.config.adaptive_quantum = Some(crate::types::AdaptiveQuantum { min, max });
+        self
+    }
+
+    pub fn policy(mut self, policy: crate::types::SchedulingPolicy) -> Self {
+        self.config.policy = policy;
+        self
+    }
+
+    // let the timer signal switch threads that are inside an async_preemptible
+    // section, without waiting for check_preemption; code outside the sections
+    // still switches at checkpoints only. Needs PreemptSafeAlloc as the global
+    // allocator
+    pub fn async_preemptible_sections(mut self, enabled: bool) -> Self {
+        self.config.async_preemptible_sections = enabled;
+        self
+    }
+
+    // switch after this many check_preemption calls in one slice, independent
+    // of how fast the machine runs them
+    pub fn checkpoint_budget(mut self, checkpoints: u32) -> Self {
+        self.config.checkpoint_budget = Some(checkpoints);
+        self
+    }
+
+    // turn off the timer signal, e.g. to preempt on checkpoint budgets alone
+    pub fn timer_preemption(mut self, enabled: bool) -> Self {
+        self.config.timer_preemption = enabled;
+        self
+    }
+
+    // schedule from a PRNG instead of the timer; the seed is drawn per run
+    // unless set with `seed`, and LACHESIS_SEED overrides both
+    pub fn deterministic(mut self, enabled: bool) -> Self {
+        self.config.deterministic = enabled;
+        self
+    }
+
+    // deterministic scheduling that replays the run with this seed
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.config.deterministic = true;
+        self.config.seed = Some(seed);
+        self
+    }
+
+    // write every context switch to a file that `replay` can force again
+    pub fn record(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.config.record = Some(path.into());
+        self
+    }
+
+    // switch exactly where a recorded run did; a run that goes another way
+    // ends with Error::ReplayDiverged
+    pub fn replay(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.config.replay = Some(path.into());
+        self
+    }
+
+    // sleeps, budgets and time slices follow a clock that only moves when
+    // every thread is asleep, or by lachesis::advance
+    pub fn virtual_clock(mut self, enabled: bool) -> Self {
+        self.config.virtual_clock = enabled;
+        self
+    }
+
+    // WaitAll by default; threads still running when the scheduler stops are
+    // cancelled, running their destructors
+    pub fn shutdown_policy(mut self, policy: crate::types::ShutdownPolicy) -> Self {
+        self.config.shutdown_policy = policy;
+        self
+    }
+
+    // how long cancelled threads get to unwind before they are dropped
+    // without running the rest of their destructors
+    pub fn shutdown_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.config.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    // live threads, the main one included; try_spawn beyond it fails with
+    // CapacityExceeded and spawn_wait waits for a thread to exit
+    pub fn max_threads(mut self, threads: usize) -> Self {
+        self.config.max_threads = Some(threads);
+        self
+    }
+
+    // bytes of stack of the live threads, with the same effect as max_threads
+    pub fn max_stack_memory(mut self, bytes: usize) -> Self {
+        self.config.max_stack_memory = Some(bytes);
+        self
+    }
+
+    // bytes of stack kept for reuse by later spawns once their threads exit;
+    // 32MiB by default, 0 frees every stack right away
+    pub fn stack_pool_limit(mut self, bytes: usize) -> Self {
+        self.config.stack_pool_limit = bytes;
+        self
+    }
+
+    // inaccessible memory below every stack that an overflow runs into;
+    // rounded up to whole pages, 16KiB by default
+    pub fn stack_guard_size(mut self, bytes: usize) -> Self {
+        self.config.stack_guard_size = bytes;
         self
     }
 
@@ -76,6 +436,29 @@ impl ConfigBuilder {
         Lachesis {
             config: self.config,
             initialized: std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            injector: std::sync::Arc::new(crate::handle::Injector::new()),
         }
     }
 }
+
+fn thread_cpu_time() -> std::time::Duration {
+    nix::time::clock_gettime(nix::time::ClockId::CLOCK_THREAD_CPUTIME_ID)
+        .map_or(std::time::Duration::ZERO, std::time::Duration::from)
+}
+
+// a main thread that left no outcome was dropped without finishing, by a
+// shutdown that did not wait for it
+pub(crate) fn main_result<T>(outcome: Option<crate::error::Result<T>>) -> crate::error::Result<T> {
+    outcome.unwrap_or(Err(crate::error::Error::Shutdown))
+}
+
+// used by #[lachesis::main] and #[lachesis::test]; runs func as the first green
+// thread and hands back its result, re-raising its panic on the caller
+#[doc(hidden)]
+pub fn run_entry<T, F>(scheduler: Lachesis, func: F) -> crate::error::Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    scheduler.run(func)
+}
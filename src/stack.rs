@@ -0,0 +1,238 @@
+// thread stacks are anonymous mappings with a guard region below them, so
+// only the pages a thread touches are committed
+#[derive(Debug, Clone, Copy)]
+pub struct Stack {
+    // start of the mapping, where the guard region begins
+    base: *mut u8,
+    guard: usize,
+    size: usize,
+}
+
+impl Stack {
+    pub fn bottom(&self) -> *mut u8 {
+        unsafe { self.base.add(self.guard) }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn top(&self) -> *mut u8 {
+        unsafe { self.bottom().add(self.size) }
+    }
+
+    pub fn guard_contains(&self, address: usize) -> bool {
+        (self.base as usize..self.bottom() as usize).contains(&address)
+    }
+}
+
+// stacks of exited threads kept for reuse, in power-of-two size classes; the
+// pages of a cached stack are given back to the OS, its mapping is kept
+pub struct Pool {
+    classes: std::collections::BTreeMap<usize, Vec<Stack>>,
+    cached: usize,
+    limit: usize,
+    guard: usize,
+    // the stack of the thread that exited last, still under the feet of the
+    // scheduler until it switches to another thread
+    retiring: Option<Stack>,
+}
+
+pub const DEFAULT_POOL_LIMIT: usize = 32 * 1024 * 1024;
+pub const DEFAULT_GUARD_SIZE: usize = 4 * crate::context::PAGE_SIZE;
+
+impl Pool {
+    pub fn new() -> Self {
+        Pool {
+            classes: std::collections::BTreeMap::new(),
+            cached: 0,
+            limit: DEFAULT_POOL_LIMIT,
+            guard: DEFAULT_GUARD_SIZE,
+            retiring: None,
+        }
+    }
+
+    fn give(&mut self, stack: Stack) {
+        if self.cached + stack.size > self.limit || stack.guard != self.guard {
+            unsafe { unmap(stack) };
+            return;
+        }
+
+        if let Some(bottom) = std::ptr::NonNull::new(stack.bottom() as *mut std::ffi::c_void) {
+            #[cfg(target_os = "linux")]
+            let advice = nix::sys::mman::MmapAdvise::MADV_DONTNEED;
+            #[cfg(not(target_os = "linux"))]
+            let advice = nix::sys::mman::MmapAdvise::MADV_FREE;
+            // only a hint; the stack is still usable if it fails
+            let _ = unsafe { nix::sys::mman::madvise(bottom, stack.size, advice) };
+        }
+        self.cached += stack.size;
+        self.classes.entry(stack.size).or_default().push(stack);
+    }
+
+    // unmap cached stacks, the largest first, until at most keep bytes are left
+    fn trim(&mut self, keep: usize) {
+        while self.cached > keep {
+            let Some(mut entry) = self.classes.last_entry() else {
+                break;
+            };
+            if let Some(stack) = entry.get_mut().pop() {
+                self.cached -= stack.size;
+                unsafe { unmap(stack) };
+            }
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        }
+    }
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// dropped with its runtime, on the OS thread's own stack
+impl Drop for Pool {
+    fn drop(&mut self) {
+        if let Some(stack) = self.retiring.take() {
+            unsafe { unmap(stack) };
+        }
+        self.trim(0);
+    }
+}
+
+fn pool() -> *mut Pool {
+    unsafe { &raw mut (*crate::runtime::runtime()).stacks }
+}
+
+// the size a stack of stack_size bytes is given
+pub fn class(stack_size: usize) -> Option<usize> {
+    stack_size.checked_next_power_of_two()
+}
+
+// a stack with its guard region in place, from the pool if one of the size
+// class is cached
+pub fn take(stack_size: usize) -> crate::error::Result<Stack> {
+    let invalid = crate::error::Error::InvalidStackSize {
+        size: stack_size,
+        min: crate::context::MIN_STACK_SIZE,
+    };
+    if stack_size < crate::context::MIN_STACK_SIZE {
+        return Err(invalid);
+    }
+    let size = class(stack_size).ok_or(invalid)?;
+
+    unsafe {
+        let pool = pool();
+        if let Some(stack) = (*pool).classes.get_mut(&size).and_then(Vec::pop) {
+            (*pool).cached -= size;
+            return Ok(stack);
+        }
+        map(size, (*pool).guard)
+    }
+}
+
+// reserve address space only; pages are committed as the thread touches them
+unsafe fn map(size: usize, guard: usize) -> crate::error::Result<Stack> {
+    let failed = |what: String| {
+        move |errno: nix::errno::Errno| crate::error::Error::SystemResource {
+            what,
+            source: errno.into(),
+        }
+    };
+    let length = size
+        .checked_add(guard)
+        .and_then(std::num::NonZeroUsize::new)
+        .ok_or(crate::error::Error::InvalidStackSize {
+            size,
+            min: crate::context::MIN_STACK_SIZE,
+        })?;
+
+    unsafe {
+        let base = nix::sys::mman::mmap_anonymous(
+            None,
+            length,
+            nix::sys::mman::ProtFlags::PROT_READ | nix::sys::mman::ProtFlags::PROT_WRITE,
+            nix::sys::mman::MapFlags::MAP_PRIVATE
+                | nix::sys::mman::MapFlags::MAP_ANONYMOUS
+                | nix::sys::mman::MapFlags::MAP_NORESERVE,
+        )
+        .map_err(failed(format!("cannot map a stack of {size} bytes")))?;
+
+        // set up the guard region for stack overflow protection
+        if let Err(errno) =
+            nix::sys::mman::mprotect(base, guard, nix::sys::mman::ProtFlags::PROT_NONE)
+        {
+            let _ = nix::sys::mman::munmap(base, length.get());
+            return Err(failed("cannot protect the stack guard region".to_string())(
+                errno,
+            ));
+        }
+
+        Ok(Stack {
+            base: base.as_ptr() as *mut u8,
+            guard,
+            size,
+        })
+    }
+}
+
+unsafe fn unmap(stack: Stack) {
+    if let Some(base) = std::ptr::NonNull::new(stack.base as *mut std::ffi::c_void) {
+        // a stack that cannot be unmapped is leaked
+        let _ = unsafe { nix::sys::mman::munmap(base, stack.guard + stack.size) };
+    }
+}
+
+// the stack of a thread that is not running and never will again
+pub fn give(stack: Stack) {
+    unsafe { (*pool()).give(stack) }
+}
+
+// the stack of the exiting thread, still in use; it goes to the pool once the
+// next thread exits, or is unmapped with the runtime
+pub fn retire(stack: Stack) {
+    unsafe {
+        let pool = pool();
+        if let Some(previous) = (*pool).retiring.replace(stack) {
+            (*pool).give(previous);
+        }
+    }
+}
+
+pub fn set_pool_limit(limit: usize) {
+    unsafe {
+        let pool = pool();
+        (*pool).limit = limit;
+        (*pool).trim(limit);
+    }
+}
+
+// rounded up to whole pages; stacks already cached with another guard are
+// unmapped instead of reused
+pub fn set_guard_size(guard: usize) {
+    unsafe {
+        let pool = pool();
+        (*pool).guard = guard.div_ceil(crate::context::PAGE_SIZE) * crate::context::PAGE_SIZE;
+        (*pool).trim(0);
+    }
+}
+
+// unmap cached stacks until at most keep bytes are left in the pool; the pool
+// belongs to the running scheduler, so outside one there is nothing to trim
+pub fn trim_stack_pool(keep: usize) {
+    if crate::runtime::current().is_null() {
+        return;
+    }
+    crate::preempt::internal(|| unsafe { (*pool()).trim(keep) })
+}
+
+// bytes of stack cached for reuse, 0 outside a scheduler
+pub fn cached_stack_bytes() -> usize {
+    if crate::runtime::current().is_null() {
+        return 0;
+    }
+    unsafe { (*pool()).cached }
+}
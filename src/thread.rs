@@ -0,0 +1,155 @@
+pub struct ThreadBuilder {
+    stack_size: Option<usize>,
+    quantum: Option<std::time::Duration>,
+    cpu_budget: Option<crate::types::CpuBudget>,
+    group: Option<crate::group::ThreadGroup>,
+    daemon: bool,
+    name: Option<String>,
+}
+
+impl Default for ThreadBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ThreadBuilder {
+    pub fn new() -> Self {
+        ThreadBuilder {
+            stack_size: None,
+            quantum: None,
+            cpu_budget: None,
+            group: None,
+            daemon: false,
+            name: None,
+        }
+    }
+
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    // time slice for this thread, overriding the scheduler-wide interval; at
+    // least MIN_QUANTUM, or the spawn fails with Configuration
+    pub fn quantum(mut self, quantum: std::time::Duration) -> Self {
+        self.quantum = Some(quantum);
+        self
+    }
+
+    pub fn cpu_budget(mut self, budget: crate::types::CpuBudget) -> Self {
+        self.cpu_budget = Some(budget);
+        self
+    }
+
+    // defaults to the group of the spawning thread
+    pub fn group(mut self, group: &crate::group::ThreadGroup) -> Self {
+        self.group = Some(*group);
+        self
+    }
+
+    // shown in diagnostics such as a stack overflow report
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    // a background thread that does not keep the scheduler running; it is
+    // cancelled once the other threads are done
+    pub fn daemon(mut self, daemon: bool) -> Self {
+        self.daemon = daemon;
+        self
+    }
+
+    // a thread spawned while the scheduler shuts down is cancelled before it runs
+    pub fn spawn<F>(self, func: F) -> crate::types::ThreadId
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn_context(Box::new(func))
+            .unwrap_or_else(|e| panic!("cannot spawn a green thread: {e}"))
+    }
+
+    // NotInitialized outside a scheduler, InvalidStackSize or SystemResource
+    // when the stack cannot be set up, Configuration for a quantum below
+    // MIN_QUANTUM or a total CPU budget with the Throttle policy, SpawnFailed
+    // while the scheduler shuts down
+    pub fn try_spawn<F>(self, func: F) -> crate::error::Result<crate::types::ThreadId>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if crate::runtime::current().is_null() {
+            return Err(crate::error::Error::NotInitialized);
+        }
+        if crate::runtime::is_shutting_down() {
+            return Err(crate::error::Error::SpawnFailed);
+        }
+        self.spawn_context(Box::new(func))
+    }
+
+    // try_spawn, but a spawn refused for capacity parks the calling green
+    // thread until another thread exits and then tries again
+    pub fn spawn_wait<F>(self, func: F) -> crate::error::Result<crate::types::ThreadId>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if crate::runtime::current().is_null() {
+            return Err(crate::error::Error::NotInitialized);
+        }
+
+        loop {
+            if crate::runtime::is_shutting_down() {
+                return Err(crate::error::Error::SpawnFailed);
+            }
+            match crate::preempt::internal(|| self.prepare()) {
+                Ok(mut ctx) => {
+                    ctx.executable = Some(Box::new(func));
+                    return Ok(crate::runtime::spawn_context(ctx));
+                }
+                Err(crate::error::Error::CapacityExceeded { .. }) => {
+                    crate::runtime::wait_for_capacity()?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn spawn_context(
+        self,
+        executable: Box<dyn crate::types::Executable>,
+    ) -> crate::error::Result<crate::types::ThreadId> {
+        let mut ctx = crate::preempt::internal(|| self.prepare())?;
+        ctx.executable = Some(executable);
+        Ok(crate::runtime::spawn_context(ctx))
+    }
+
+    fn prepare(&self) -> crate::error::Result<Box<crate::context::Context>> {
+        if let Some(quantum) = self.quantum {
+            crate::timer::check_quantum("quantum", quantum)?;
+        }
+
+        // a total budget never refills, so throttling would park the thread forever
+        if let Some(budget) = self.cpu_budget
+            && budget.period.is_none()
+            && budget.policy == crate::types::BudgetPolicy::Throttle
+        {
+            return Err(crate::error::Error::Configuration(
+                "a total CPU budget cannot be throttled, only terminate the thread".to_string(),
+            ));
+        }
+
+        let stack_size = self
+            .stack_size
+            .unwrap_or_else(crate::runtime::default_stack_size);
+
+        let mut ctx = crate::runtime::new_context(stack_size)?;
+        ctx.quantum = self.quantum;
+        ctx.budget = self.cpu_budget.map(crate::budget::Budget::new);
+        ctx.daemon = self.daemon;
+        ctx.name = self.name.clone();
+        ctx.group = self
+            .group
+            .map_or_else(crate::runtime::current_group, |g| g.id());
+        Ok(ctx)
+    }
+}
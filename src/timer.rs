@@ -1,81 +1,716 @@
-static mut TIMER_THREAD_HANDLE: Option<std::thread::JoinHandle<()>> = None;
-static mut TIMER_ENABLED: bool = false;
+// the timer state of one runtime
+pub struct State {
+    handle: Option<std::thread::JoinHandle<()>>,
+    enabled: bool,
+    adaptive_quantum: Option<crate::types::AdaptiveQuantum>,
+    backend: crate::types::TimerBackend,
+    signal: nix::sys::signal::Signal,
+    // the signal handler is installed for this runtime
+    installed: bool,
+    #[cfg(target_os = "linux")]
+    posix: Option<nix::sys::timer::Timer>,
+    // whether the running thread has a quantum of its own
+    slice_pinned: bool,
+    // whether ticks come from the timer signal at all
+    timer_preemption: bool,
+    // check_preemption calls per slice, 0 when not counting
+    checkpoint_budget: u32,
+    checkpoints_left: u32,
+    // whether a checkpoint only has the tick flag and the budget to look at,
+    // with no recording, schedule exploration or virtual clock to serve
+    fast_checkpoints: bool,
+    async_sections: bool,
+    shared: std::sync::Arc<Shared>,
+}
+
+// what the timer thread reads and the signal handler sets
+struct Shared {
+    interval_ns: std::sync::atomic::AtomicU64,
+    // start and length of the running thread's time slice
+    slice_start_ns: std::sync::atomic::AtomicU64,
+    slice_quantum_ns: std::sync::atomic::AtomicU64,
+    stop: std::sync::atomic::AtomicBool,
+    // when the timer thread is parked until; a slice ending earlier unparks it
+    sleeping_until_ns: std::sync::atomic::AtomicU64,
+    // set by the timer thread right before it signals the scheduler thread,
+    // where a tick cannot carry TIMER_COOKIE
+    tick_pending: std::sync::atomic::AtomicBool,
+    preemption_requested: std::sync::atomic::AtomicBool,
+}
+
+impl State {
+    pub fn new() -> Self {
+        State {
+            handle: None,
+            enabled: false,
+            adaptive_quantum: None,
+            backend: crate::types::TimerBackend::Thread,
+            signal: nix::sys::signal::Signal::SIGALRM,
+            installed: false,
+            #[cfg(target_os = "linux")]
+            posix: None,
+            slice_pinned: false,
+            timer_preemption: true,
+            checkpoint_budget: 0,
+            checkpoints_left: 0,
+            fast_checkpoints: false,
+            async_sections: false,
+            shared: std::sync::Arc::new(Shared {
+                interval_ns: std::sync::atomic::AtomicU64::new(10_000_000),
+                slice_start_ns: std::sync::atomic::AtomicU64::new(0),
+                slice_quantum_ns: std::sync::atomic::AtomicU64::new(10_000_000),
+                stop: std::sync::atomic::AtomicBool::new(false),
+                sleeping_until_ns: std::sync::atomic::AtomicU64::new(u64::MAX),
+                tick_pending: std::sync::atomic::AtomicBool::new(false),
+                preemption_requested: std::sync::atomic::AtomicBool::new(false),
+            }),
+        }
+    }
+}
+
+// the signal handler is process-wide: the first running scheduler that uses a
+// signal installs it and the last one gives the signal back; counted by
+// signal number
+static HANDLER_USERS: std::sync::Mutex<[usize; 65]> = std::sync::Mutex::new([0; 65]);
+// the handler that was installed for each signal before ours, only written
+// under HANDLER_USERS
+static mut PREVIOUS_ACTIONS: [Option<nix::sys::signal::SigAction>; 65] = [None; 65];
+// si_value of the signals sent by the kernel timer and the timer thread
+const TIMER_COOKIE: usize = 0x6c61_6368;
+
+// the timer state of the runtime on this OS thread
+fn state() -> *mut State {
+    let runtime = crate::runtime::current();
+    if runtime.is_null() {
+        return std::ptr::null_mut();
+    }
+    unsafe { &raw mut (*runtime).timer }
+}
+
+extern "C" fn preemption_signal_handler(
+    signal: i32,
+    info: *mut nix::libc::siginfo_t,
+    ucontext: *mut std::ffi::c_void,
+) {
+    // a signal on an OS thread without a scheduler is not a tick
+    let state = state();
+    let ours = !state.is_null()
+        && unsafe {
+            let shared = &(*state).shared;
+            is_timer_tick(info)
+                || shared
+                    .tick_pending
+                    .swap(false, std::sync::atomic::Ordering::Relaxed)
+        };
+    if !ours {
+        unsafe { chain_previous(signal, info, ucontext) };
+        return;
+    }
+
+    unsafe {
+        let shared = &(*state).shared;
+        shared
+            .preemption_requested
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+
+        if (*state).async_sections && crate::preempt::depth() == 0 {
+            crate::runtime::preempt_from_signal();
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn is_timer_tick(info: *mut nix::libc::siginfo_t) -> bool {
+    unsafe {
+        let queued =
+            (*info).si_code == nix::libc::SI_QUEUE && (*info).si_pid() == nix::libc::getpid();
+        ((*info).si_code == nix::libc::SI_TIMER || queued)
+            && (*info).si_value().sival_ptr as usize == TIMER_COOKIE
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn is_timer_tick(_: *mut nix::libc::siginfo_t) -> bool {
+    false
+}
+
+// hand a signal we did not send to whoever had the signal before us
+unsafe fn chain_previous(
+    signal: i32,
+    info: *mut nix::libc::siginfo_t,
+    ucontext: *mut std::ffi::c_void,
+) {
+    unsafe {
+        let previous_ptr = &raw const PREVIOUS_ACTIONS;
+        let previous = (*previous_ptr).get(signal as usize).copied().flatten();
+        match previous.map(|a| a.handler()) {
+            Some(nix::sys::signal::SigHandler::Handler(handler)) => handler(signal),
+            Some(nix::sys::signal::SigHandler::SigAction(handler)) => {
+                handler(signal, info, ucontext)
+            }
+            Some(nix::sys::signal::SigHandler::SigDfl) => default_action(signal),
+            _ => {}
+        }
+    }
+}
+
+// run the default action of a stray signal: raise it again without our handler,
+// which comes back if the process was only stopped; ignoring is left as it is,
+// as the handler would be missing for the ticks of the other schedulers meanwhile
+unsafe fn default_action(signal: i32) {
+    let Ok(signal) = nix::sys::signal::Signal::try_from(signal) else {
+        return;
+    };
+    if matches!(
+        signal,
+        nix::sys::signal::Signal::SIGCHLD
+            | nix::sys::signal::Signal::SIGCONT
+            | nix::sys::signal::Signal::SIGURG
+            | nix::sys::signal::Signal::SIGWINCH
+    ) {
+        return;
+    }
 
-extern "C" fn preemption_signal_handler(_: i32) {
-    crate::types::PREEMPTION_REQUESTED.store(true, std::sync::atomic::Ordering::Relaxed);
+    let default = nix::sys::signal::SigAction::new(
+        nix::sys::signal::SigHandler::SigDfl,
+        nix::sys::signal::SaFlags::empty(),
+        nix::sys::signal::SigSet::empty(),
+    );
+    unsafe {
+        if let Ok(ours) = nix::sys::signal::sigaction(signal, &default) {
+            let _ = nix::sys::signal::raise(signal);
+            let _ = nix::sys::signal::sigaction(signal, &ours);
+        }
+    }
+}
+
+fn install_handler(signal: nix::sys::signal::Signal) -> crate::error::Result<()> {
+    let mut users = HANDLER_USERS.lock().unwrap_or_else(|e| e.into_inner());
+    let index = signal as usize;
+    if users[index] > 0 {
+        users[index] += 1;
+        return Ok(());
+    }
+
+    // SA_NODEFER keeps the signal unblocked for the other green threads while
+    // a preempted one is switched out inside the handler
+    let action = nix::sys::signal::SigAction::new(
+        nix::sys::signal::SigHandler::SigAction(preemption_signal_handler),
+        nix::sys::signal::SaFlags::SA_SIGINFO
+            | nix::sys::signal::SaFlags::SA_NODEFER
+            | nix::sys::signal::SaFlags::SA_RESTART,
+        nix::sys::signal::SigSet::empty(),
+    );
+    unsafe {
+        let previous = nix::sys::signal::sigaction(signal, &action).map_err(|errno| {
+            crate::error::Error::SystemResource {
+                what: format!("cannot install the {signal} handler"),
+                source: errno.into(),
+            }
+        })?;
+        let previous_ptr = &raw mut PREVIOUS_ACTIONS;
+        (*previous_ptr)[index] = Some(previous);
+    }
+    users[index] = 1;
+    Ok(())
 }
 
-pub fn init_timer(interval_ms: u64) {
+// give the signal back to its previous owner once no scheduler uses it
+fn uninstall_handler(signal: nix::sys::signal::Signal) {
+    let mut users = HANDLER_USERS.lock().unwrap_or_else(|e| e.into_inner());
+    let index = signal as usize;
+    users[index] -= 1;
+    if users[index] > 0 {
+        return;
+    }
+
     unsafe {
-        // signal handler
-        let handler = nix::sys::signal::SigHandler::Handler(preemption_signal_handler);
-        nix::sys::signal::signal(nix::sys::signal::Signal::SIGALRM, handler).unwrap();
+        let previous_ptr = &raw mut PREVIOUS_ACTIONS;
+        if let Some(previous) = (*previous_ptr)[index].take() {
+            let _ = nix::sys::signal::sigaction(signal, &previous);
+        }
+    }
+}
+
+pub fn set_signal(signal: nix::sys::signal::Signal) {
+    let state = state();
+    if !state.is_null() {
+        unsafe { (*state).signal = signal };
+    }
+}
+
+pub fn set_async_sections(enabled: bool) {
+    let state = state();
+    if !state.is_null() {
+        unsafe { (*state).async_sections = enabled };
+    }
+}
+
+pub fn set_checkpoint_budget(budget: Option<u32>) {
+    let state = state();
+    if !state.is_null() {
+        unsafe { (*state).checkpoint_budget = budget.unwrap_or(0) };
+    }
+}
+
+pub fn set_fast_checkpoints(enabled: bool) {
+    let state = state();
+    if !state.is_null() {
+        unsafe { (*state).fast_checkpoints = enabled };
+    }
+}
+
+pub fn set_timer_preemption(enabled: bool) {
+    let state = state();
+    if !state.is_null() {
+        unsafe { (*state).timer_preemption = enabled };
+    }
+}
+
+pub fn set_backend(backend: crate::types::TimerBackend) {
+    let state = state();
+    if !state.is_null() {
+        unsafe { (*state).backend = backend };
+    }
+}
+
+// a failure leaves whatever was set up for disable_preemption to take down
+pub fn init_timer(interval: std::time::Duration) -> crate::error::Result<()> {
+    let state = state();
+    if state.is_null() {
+        return Ok(());
+    }
 
+    unsafe {
+        let shared = std::sync::Arc::clone(&(*state).shared);
         // reset flags
-        crate::types::TIMER_STOP_FLAG.store(false, std::sync::atomic::Ordering::Relaxed);
-        crate::types::PREEMPTION_REQUESTED.store(false, std::sync::atomic::Ordering::Relaxed);
+        shared
+            .stop
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+        shared
+            .preemption_requested
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+        shared.interval_ns.store(
+            interval.as_nanos() as u64,
+            std::sync::atomic::Ordering::Relaxed,
+        );
+
+        (*state).enabled = true;
 
-        let handle = std::thread::spawn(move || {
-            let interval = std::time::Duration::from_millis(interval_ms);
+        // checkpoint budgets or the virtual clock only, nothing to install
+        if !(*state).timer_preemption || crate::clock::is_virtual() {
+            start_slice(None, 1);
+            return Ok(());
+        }
 
-            while !crate::types::TIMER_STOP_FLAG.load(std::sync::atomic::Ordering::Relaxed) {
-                std::thread::sleep(interval);
+        let signal = (*state).signal;
+        install_handler(signal)?;
+        (*state).installed = true;
 
-                if !crate::types::TIMER_STOP_FLAG.load(std::sync::atomic::Ordering::Relaxed) {
-                    let pid = nix::unistd::getpid();
-                    let _ = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGALRM);
-                }
+        match (*state).backend {
+            crate::types::TimerBackend::Thread => {
+                let target = nix::libc::pthread_self() as usize;
+                let handle = std::thread::spawn(move || timer_thread(shared, target, signal));
+                (*state).handle = Some(handle);
             }
-        });
+            #[cfg(target_os = "linux")]
+            crate::types::TimerBackend::Posix(clock) => {
+                let clock_id = match clock {
+                    crate::types::TimerClock::Monotonic => nix::time::ClockId::CLOCK_MONOTONIC,
+                    crate::types::TimerClock::ThreadCpuTime => {
+                        nix::time::ClockId::CLOCK_THREAD_CPUTIME_ID
+                    }
+                };
+                // delivered to the scheduler's OS thread only
+                let event =
+                    nix::sys::signal::SigEvent::new(nix::sys::signal::SigevNotify::SigevThreadId {
+                        signal,
+                        thread_id: nix::unistd::gettid().as_raw(),
+                        si_value: TIMER_COOKIE as nix::libc::intptr_t,
+                    });
+                let timer = nix::sys::timer::Timer::new(clock_id, event).map_err(|errno| {
+                    crate::error::Error::SystemResource {
+                        what: "cannot create the preemption timer".to_string(),
+                        source: errno.into(),
+                    }
+                })?;
+                (*state).posix = Some(timer);
+            }
+        }
 
-        let handle_ptr = &raw mut TIMER_THREAD_HANDLE;
-        *handle_ptr = Some(handle);
+        start_slice(None, 1);
+    }
+    Ok(())
+}
 
-        let timer_ptr = &raw mut TIMER_ENABLED;
-        *timer_ptr = true;
+impl Shared {
+    // the end of the running slice, or of the quantum after the last tick
+    // when that tick was sent for the same slice
+    fn deadline_ns(&self, ticked: Option<(u64, u64)>) -> u64 {
+        let start = self
+            .slice_start_ns
+            .load(std::sync::atomic::Ordering::SeqCst);
+        let quantum_ns = self
+            .slice_quantum_ns
+            .load(std::sync::atomic::Ordering::SeqCst)
+            .max(1);
+        match ticked {
+            Some((slice, at)) if slice == start => at + quantum_ns,
+            _ => start + quantum_ns,
+        }
     }
 }
 
+fn timer_thread(shared: std::sync::Arc<Shared>, target: usize, signal: nix::sys::signal::Signal) {
+    // the slice the last tick was sent for and when
+    let mut ticked = None;
+    while !shared.stop.load(std::sync::atomic::Ordering::SeqCst) {
+        let deadline = shared.deadline_ns(ticked);
+        let now = crate::clock::now_ns();
+
+        if now < deadline {
+            // a slice started after this store sees it and unparks us, one
+            // started before it is seen by the second look at the deadline
+            shared
+                .sleeping_until_ns
+                .store(deadline, std::sync::atomic::Ordering::SeqCst);
+            if shared.deadline_ns(ticked) == deadline {
+                std::thread::park_timeout(std::time::Duration::from_nanos(deadline - now));
+            }
+            continue;
+        }
+
+        if !shared.stop.load(std::sync::atomic::Ordering::SeqCst) {
+            unsafe { send_tick(&shared, target, signal) };
+        }
+
+        // the slice has not been switched yet, remind again after another quantum
+        let start = shared
+            .slice_start_ns
+            .load(std::sync::atomic::Ordering::SeqCst);
+        ticked = Some((start, now));
+    }
+}
+
+// tagged with TIMER_COOKIE, so a foreign signal is never taken for a tick
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+unsafe fn send_tick(_: &Shared, target: usize, signal: nix::sys::signal::Signal) {
+    let value = nix::libc::sigval {
+        sival_ptr: TIMER_COOKIE as *mut std::ffi::c_void,
+    };
+    unsafe { nix::libc::pthread_sigqueue(target as nix::libc::pthread_t, signal as i32, value) };
+}
+
+// a foreign signal that arrives between the flag and the tick is taken for it
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+unsafe fn send_tick(shared: &Shared, target: usize, signal: nix::sys::signal::Signal) {
+    shared
+        .tick_pending
+        .store(true, std::sync::atomic::Ordering::Relaxed);
+    unsafe { nix::libc::pthread_kill(target as nix::libc::pthread_t, signal as i32) };
+}
+
+// the timer thread waits for the end of the slice it last saw; wake it when
+// the running slice ends sooner
+fn wake_timer_thread(state: &State) {
+    let Some(handle) = &state.handle else {
+        return;
+    };
+    let shared = &state.shared;
+    if shared.deadline_ns(None)
+        < shared
+            .sleeping_until_ns
+            .load(std::sync::atomic::Ordering::SeqCst)
+    {
+        handle.thread().unpark();
+    }
+}
+
+// called whenever a green thread is switched in
+pub fn start_slice(quantum: Option<std::time::Duration>, ready: usize) {
+    let state = state();
+    if state.is_null() {
+        return;
+    }
+
+    unsafe {
+        let shared = &(*state).shared;
+        let quantum_ns = match quantum {
+            Some(q) => q.as_nanos() as u64,
+            None => match (*state).adaptive_quantum {
+                Some(adaptive) => adaptive.quantum_for(ready).as_nanos() as u64,
+                None => shared
+                    .interval_ns
+                    .load(std::sync::atomic::Ordering::Relaxed),
+            },
+        };
+
+        (*state).slice_pinned = quantum.is_some();
+        (*state).checkpoints_left = (*state).checkpoint_budget;
+
+        shared
+            .slice_quantum_ns
+            .store(quantum_ns, std::sync::atomic::Ordering::SeqCst);
+        shared
+            .slice_start_ns
+            .store(crate::clock::now_ns(), std::sync::atomic::Ordering::SeqCst);
+        shared
+            .preemption_requested
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+        arm_posix_timer(&mut *state, quantum_ns);
+        wake_timer_thread(&*state);
+    }
+}
+
+// fire after the quantum, then again every quantum until the next switch
+#[cfg(target_os = "linux")]
+fn arm_posix_timer(state: &mut State, quantum_ns: u64) {
+    if let Some(timer) = state.posix.as_mut() {
+        let quantum = nix::sys::time::TimeSpec::from_duration(std::time::Duration::from_nanos(
+            quantum_ns.max(1),
+        ));
+        let _ = timer.set(
+            nix::sys::timer::Expiration::IntervalDelayed(quantum, quantum),
+            nix::sys::timer::TimerSetTimeFlags::empty(),
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn arm_posix_timer(_: &mut State, _: u64) {}
+
+pub fn set_adaptive_quantum(adaptive: Option<crate::types::AdaptiveQuantum>) {
+    let state = state();
+    if !state.is_null() {
+        unsafe { (*state).adaptive_quantum = adaptive };
+    }
+}
+
+// the shortest time slice; ticks any closer would keep the scheduler's OS
+// thread busy with signals
+pub const MIN_QUANTUM: std::time::Duration = std::time::Duration::from_micros(100);
+
+pub fn check_quantum(what: &str, quantum: std::time::Duration) -> crate::error::Result<()> {
+    if quantum < MIN_QUANTUM {
+        return Err(crate::error::Error::Configuration(format!(
+            "{what} of {quantum:?} is below the minimum of {MIN_QUANTUM:?}"
+        )));
+    }
+    Ok(())
+}
+
+// change the scheduler-wide interval; threads with their own quantum keep it.
+// NotInitialized outside a scheduler, Configuration below MIN_QUANTUM
+pub fn set_preemption_interval(interval: std::time::Duration) -> crate::error::Result<()> {
+    let state = state();
+    if state.is_null() {
+        return Err(crate::error::Error::NotInitialized);
+    }
+    check_quantum("preemption interval", interval)?;
+
+    let interval_ns = interval.as_nanos() as u64;
+    unsafe {
+        let shared = std::sync::Arc::clone(&(*state).shared);
+        shared
+            .interval_ns
+            .store(interval_ns, std::sync::atomic::Ordering::Relaxed);
+
+        if !(*state).slice_pinned && (*state).adaptive_quantum.is_none() {
+            shared
+                .slice_quantum_ns
+                .store(interval_ns, std::sync::atomic::Ordering::SeqCst);
+            arm_posix_timer(&mut *state, interval_ns);
+            wake_timer_thread(&*state);
+        }
+    }
+    Ok(())
+}
+
+pub fn preemption_interval() -> std::time::Duration {
+    let state = state();
+    if state.is_null() {
+        return crate::types::SchedulerConfig::default().preemption_interval;
+    }
+
+    let interval_ns = unsafe {
+        let shared = &(*state).shared;
+        shared
+            .interval_ns
+            .load(std::sync::atomic::Ordering::Relaxed)
+    };
+    std::time::Duration::from_nanos(interval_ns)
+}
+
 // indicate safe preemption points
 pub fn check_preemption() {
+    // the common case touches the thread-local once: no tick has arrived and
+    // the budget is not used up, so there is nothing to switch for
+    let runtime = crate::runtime::current();
+    if runtime.is_null() {
+        return;
+    }
+    unsafe {
+        let state = &raw mut (*runtime).timer;
+        let shared = &(*state).shared;
+        if (*state).fast_checkpoints
+            && !shared
+                .preemption_requested
+                .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            if (*state).checkpoint_budget == 0 {
+                return;
+            }
+            if (*state).checkpoints_left > 1 {
+                (*state).checkpoints_left -= 1;
+                return;
+            }
+        }
+    }
+    checkpoint();
+}
+
+// a checkpoint that may switch, or has to be seen by a recording, the schedule
+// exploration or the virtual clock
+pub fn checkpoint() {
+    // a manually driven thread stops here and waits for the test
+    if crate::runtime::is_manual() {
+        if crate::preempt::depth() == 0 {
+            crate::record::on_checkpoint(crate::runtime::current_thread_id());
+            crate::runtime::schedule_for(crate::record::Reason::Checkpoint);
+        }
+        return;
+    }
+
     if !is_preemption_enabled() {
         return;
     }
+    crate::record::on_checkpoint(crate::runtime::current_thread_id());
+    let state = state();
+
+    // count down the slice's checkpoints; an exhausted budget stays at zero
+    // until the next slice, so a critical section still switches when it ends
+    let exhausted = unsafe {
+        if (*state).checkpoint_budget == 0 {
+            false
+        } else {
+            (*state).checkpoints_left = (*state).checkpoints_left.saturating_sub(1);
+            (*state).checkpoints_left == 0
+        }
+    };
+
+    // inside a critical section; the flag stays set for the outermost guard
+    if crate::preempt::depth() > 0 {
+        return;
+    }
+
+    if crate::deterministic::is_enabled() {
+        let running = crate::runtime::current_thread_id();
+        if exhausted || crate::deterministic::should_yield(running) {
+            refill_checkpoints();
+            crate::runtime::schedule_for(crate::record::Reason::Checkpoint);
+        }
+        return;
+    }
+
+    let shared = unsafe { std::sync::Arc::clone(&(*state).shared) };
+    if exhausted {
+        shared
+            .preemption_requested
+            .store(false, std::sync::atomic::Ordering::Release);
+        refill_checkpoints();
+        crate::runtime::schedule_for(crate::record::Reason::Checkpoint);
+        return;
+    }
+
+    if unsafe { !(*state).timer_preemption } {
+        return;
+    }
+
+    // no signal on virtual time, the slice is over once the clock says so
+    if crate::clock::is_virtual() {
+        let start = shared
+            .slice_start_ns
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let quantum = shared
+            .slice_quantum_ns
+            .load(std::sync::atomic::Ordering::Relaxed);
+        if crate::clock::now_ns() >= start + quantum {
+            crate::runtime::schedule_for(crate::record::Reason::Checkpoint);
+        }
+        return;
+    }
 
     // Check atomic flag set by signal handler
-    if crate::types::PREEMPTION_REQUESTED.load(std::sync::atomic::Ordering::Acquire) {
-        crate::types::PREEMPTION_REQUESTED.store(false, std::sync::atomic::Ordering::Release);
-        crate::runtime::schedule();
+    if shared
+        .preemption_requested
+        .load(std::sync::atomic::Ordering::Acquire)
+    {
+        shared
+            .preemption_requested
+            .store(false, std::sync::atomic::Ordering::Release);
+        crate::runtime::schedule_for(crate::record::Reason::Checkpoint);
     }
 }
 
-pub fn enable_preemption_with_interval(interval_ms: u64) {
+// a thread that keeps running after its budget ran out, e.g. when it is the
+// only one, starts counting again
+fn refill_checkpoints() {
+    let state = state();
+    if !state.is_null() {
+        unsafe { (*state).checkpoints_left = (*state).checkpoint_budget };
+    }
+}
+
+pub fn enable_preemption_with_interval(interval: std::time::Duration) -> crate::error::Result<()> {
+    let state = state();
+    if state.is_null() {
+        return Ok(());
+    }
+
     unsafe {
-        let timer_ptr = &raw mut TIMER_ENABLED;
-        *timer_ptr = true;
-        crate::types::PREEMPTION_REQUESTED.store(false, std::sync::atomic::Ordering::Relaxed);
-        init_timer(interval_ms);
+        (*state).enabled = true;
+        let shared = &(*state).shared;
+        shared
+            .preemption_requested
+            .store(false, std::sync::atomic::Ordering::Relaxed);
     }
+    init_timer(interval)
 }
 
 pub fn disable_preemption() {
+    let state = state();
+    if state.is_null() {
+        return;
+    }
+
     unsafe {
-        crate::types::TIMER_STOP_FLAG.store(true, std::sync::atomic::Ordering::Relaxed);
+        let shared = std::sync::Arc::clone(&(*state).shared);
+        shared.stop.store(true, std::sync::atomic::Ordering::SeqCst);
 
-        let handle_ptr = &raw mut TIMER_THREAD_HANDLE;
-        if let Some(handle) = (*handle_ptr).take() {
+        if let Some(handle) = (*state).handle.take() {
+            handle.thread().unpark();
             let _ = handle.join();
         }
 
-        let timer_ptr = &raw mut TIMER_ENABLED;
-        *timer_ptr = false;
-        crate::types::PREEMPTION_REQUESTED.store(false, std::sync::atomic::Ordering::Release);
+        // dropping the timer deletes it
+        #[cfg(target_os = "linux")]
+        {
+            (*state).posix = None;
+        }
+
+        if (*state).installed {
+            (*state).installed = false;
+            uninstall_handler((*state).signal);
+        }
+
+        (*state).enabled = false;
+        shared
+            .preemption_requested
+            .store(false, std::sync::atomic::Ordering::Release);
     }
 }
 
 pub fn is_preemption_enabled() -> bool {
-    unsafe {
-        let timer_ptr = &raw const TIMER_ENABLED;
-        *timer_ptr
-    }
+    let state = state();
+    !state.is_null() && unsafe { (*state).enabled }
 }
@@ -19,6 +19,11 @@ where
 pub enum ThreadState {
     Ready,
     Running,
+    Throttled,
+    // in lachesis::sleep until its wake-up time
+    Sleeping,
+    // in spawn_wait until another thread exits
+    WaitingForCapacity,
     Terminated,
 }
 
@@ -31,19 +36,156 @@ pub struct ThreadInfo {
 #[derive(Debug, Clone)]
 pub struct SchedulerConfig {
     pub default_stack_size: usize,
-    pub preemption_interval_ms: u64,
+    pub preemption_interval: std::time::Duration,
+    pub timer_backend: TimerBackend,
+    pub preemption_signal: nix::sys::signal::Signal,
+    pub adaptive_quantum: Option<AdaptiveQuantum>,
+    pub policy: SchedulingPolicy,
+    pub async_preemptible_sections: bool,
+    pub checkpoint_budget: Option<u32>,
+    pub timer_preemption: bool,
+    pub deterministic: bool,
+    pub seed: Option<u64>,
+    pub record: Option<std::path::PathBuf>,
+    pub replay: Option<std::path::PathBuf>,
+    pub virtual_clock: bool,
+    pub shutdown_policy: ShutdownPolicy,
+    pub shutdown_timeout: Option<std::time::Duration>,
+    pub max_threads: Option<usize>,
+    pub max_stack_memory: Option<usize>,
+    pub stack_pool_limit: usize,
+    pub stack_guard_size: usize,
 }
 
 impl Default for SchedulerConfig {
     fn default() -> Self {
         SchedulerConfig {
             default_stack_size: 2 * 1024 * 1024, // 2MB
-            preemption_interval_ms: 10,
+            preemption_interval: std::time::Duration::from_millis(10),
+            timer_backend: TimerBackend::Thread,
+            preemption_signal: nix::sys::signal::Signal::SIGALRM,
+            adaptive_quantum: None,
+            policy: SchedulingPolicy::FairShare,
+            async_preemptible_sections: false,
+            checkpoint_budget: None,
+            timer_preemption: true,
+            deterministic: false,
+            seed: None,
+            record: None,
+            replay: None,
+            virtual_clock: false,
+            shutdown_policy: ShutdownPolicy::WaitAll,
+            shutdown_timeout: None,
+            max_threads: None,
+            max_stack_memory: None,
+            stack_pool_limit: crate::stack::DEFAULT_POOL_LIMIT,
+            stack_guard_size: crate::stack::DEFAULT_GUARD_SIZE,
         }
     }
 }
 
-pub static TIMER_STOP_FLAG: std::sync::atomic::AtomicBool =
-    std::sync::atomic::AtomicBool::new(false);
-pub static PREEMPTION_REQUESTED: std::sync::atomic::AtomicBool =
-    std::sync::atomic::AtomicBool::new(false);
+// what a run did, to compare configurations
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunReport {
+    // times a different green thread was switched in, the first one included
+    pub switches: u64,
+    pub threads_spawned: u64,
+    pub peak_threads: usize,
+    // bytes of the stacks of the threads alive at once
+    pub peak_stack_memory: usize,
+    pub wall_time: std::time::Duration,
+    // CPU time of the scheduler's OS thread
+    pub cpu_time: std::time::Duration,
+    // the seed of a deterministic run, which replays it
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulingPolicy {
+    // one queue, ignoring thread groups
+    RoundRobin,
+    // CPU time is divided among thread groups by their shares, then among their threads
+    FairShare,
+}
+
+// when the scheduler stops; threads still running then are cancelled, and
+// daemon threads never keep it running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownPolicy {
+    // once every thread that is not a daemon has finished
+    WaitAll,
+    // once the main green thread returns
+    MainExits,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerBackend {
+    // a helper thread that sleeps through each slice and then signals the
+    // scheduler's OS thread
+    Thread,
+    // a kernel interval timer (timer_create) delivering the signal itself
+    #[cfg(target_os = "linux")]
+    Posix(TimerClock),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerClock {
+    Monotonic,
+    // CPU time consumed by the scheduler's OS thread
+    ThreadCpuTime,
+}
+
+// the target latency (max) is split among the ready threads,
+// but no thread runs for less than min
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveQuantum {
+    pub min: std::time::Duration,
+    pub max: std::time::Duration,
+}
+
+impl AdaptiveQuantum {
+    pub fn quantum_for(&self, ready: usize) -> std::time::Duration {
+        (self.max / ready.max(1) as u32).clamp(self.min, self.max)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetPolicy {
+    Throttle,
+    Terminate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuBudget {
+    pub limit: std::time::Duration,
+    pub period: Option<std::time::Duration>,
+    pub policy: BudgetPolicy,
+}
+
+impl CpuBudget {
+    // total CPU time the thread may use over its whole life
+    pub fn total(limit: std::time::Duration) -> Self {
+        CpuBudget {
+            limit,
+            period: None,
+            policy: BudgetPolicy::Terminate,
+        }
+    }
+
+    // CPU time the thread may use in every period
+    pub fn rate(limit: std::time::Duration, period: std::time::Duration) -> Self {
+        CpuBudget {
+            limit,
+            period: Some(period),
+            policy: BudgetPolicy::Throttle,
+        }
+    }
+
+    pub fn policy(mut self, policy: BudgetPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+// unwinding payload used to terminate a green thread from inside the runtime
+pub struct Cancelled(pub crate::error::Error);